@@ -1,8 +1,44 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use lunatic_vm::linker::LunaticLinker;
 use lunatic_vm::module::LunaticModule;
-use lunatic_vm::process::MemoryChoice;
-use wasmtime::{Engine, Linker, Module, Store};
+use lunatic_vm::normalisation::{CostModel, Pipeline};
+use lunatic_vm::process::{FunctionLookup, MemoryChoice, Process, ProcessConfig, EXECUTOR};
+use smol::future;
+use wasmtime::{Engine, Linker, Module, Store, Val};
+
+const SPIN_ITERATIONS: i32 = 1_000_000;
+
+// A loop without calls, it can only be preempted through the reduction counting in its header.
+// `_start` does nothing and `spin_process` spins `SPIN_ITERATIONS` times, both to be spawned.
+fn spin_module() -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"
+        (module
+            (func $spin (export "spin") (param i32)
+                loop
+                    local.get 0
+                    i32.const 1
+                    i32.sub
+                    local.tee 0
+                    br_if 0
+                end
+            )
+            (func (export "_start"))
+            (func (export "spin_process")
+                i32.const {}
+                call $spin
+            )
+        )
+        "#,
+        SPIN_ITERATIONS
+    ))
+    .unwrap()
+}
+
+const COST_MODELS: [(&str, CostModel); 2] = [
+    ("uniform", CostModel::Uniform),
+    ("weighted", CostModel::Weighted),
+];
 
 fn lunatic_bench(c: &mut Criterion) {
     c.bench_function("wasmtime instance creation", |b| {
@@ -29,7 +65,14 @@ fn lunatic_bench(c: &mut Criterion) {
         let module = LunaticModule::new(wasm.as_ref().into()).unwrap();
 
         b.iter(move || {
-            let linker = LunaticLinker::new(module.clone(), 0, MemoryChoice::New).unwrap();
+            let linker = LunaticLinker::new(
+                module.clone(),
+                0,
+                0,
+                MemoryChoice::New,
+                ProcessConfig::default(),
+            )
+            .unwrap();
             linker.instance().unwrap()
         });
     });
@@ -42,7 +85,14 @@ fn lunatic_bench(c: &mut Criterion) {
         b.iter_custom(move |iters| {
             let start = std::time::Instant::now();
             (0..iters).into_par_iter().for_each(|_i| {
-                let linker = LunaticLinker::new(module.clone(), 0, MemoryChoice::New).unwrap();
+                let linker = LunaticLinker::new(
+                    module.clone(),
+                    0,
+                    0,
+                    MemoryChoice::New,
+                    ProcessConfig::default(),
+                )
+                .unwrap();
                 criterion::black_box(linker.instance().unwrap());
             });
             start.elapsed()
@@ -50,5 +100,62 @@ fn lunatic_bench(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, lunatic_bench);
+// Measures the overhead of reduction counting, the only way processes are preempted. Wasmtime 0.21
+// has neither fuel nor epochs, see `linker::engine`. An engine based scheduling mode should be
+// compared against these numbers once it's available.
+fn reduction_counting_bench(c: &mut Criterion) {
+    let wasm = include_bytes!("start.wasm");
+    for &(name, cost_model) in &COST_MODELS {
+        c.bench_function(&format!("lunatic module compilation ({})", name), |b| {
+            let pipeline = Pipeline::with_cost_model(cost_model);
+            b.iter(|| LunaticModule::with_pipeline(wasm.as_ref().into(), &pipeline).unwrap());
+        });
+    }
+
+    let spin = spin_module();
+
+    c.bench_function("wasmtime spin loop (no reduction counting)", |b| {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &spin).unwrap();
+        let store = Store::new(&engine);
+        let instance = Linker::new(&store).instantiate(&module).unwrap();
+        let func = instance.get_func("spin").unwrap();
+        b.iter(|| func.call(&[Val::I32(SPIN_ITERATIONS)]).unwrap());
+    });
+
+    for &(name, cost_model) in &COST_MODELS {
+        let module =
+            LunaticModule::with_pipeline(spin.clone(), &Pipeline::with_cost_model(cost_model))
+                .unwrap();
+
+        c.bench_function(&format!("lunatic spin loop ({})", name), |b| {
+            // The loop doesn't run inside of a process that could yield.
+            let config = ProcessConfig {
+                reduction_limit: u32::MAX,
+                ..ProcessConfig::default()
+            };
+            let linker =
+                LunaticLinker::new(module.clone(), 0, 0, MemoryChoice::New, config).unwrap();
+            let func = linker.instance().unwrap().get_func("spin").unwrap();
+            b.iter(|| func.call(&[Val::I32(SPIN_ITERATIONS)]).unwrap());
+        });
+
+        // Spawn time and throughput of whole processes, including yielding to the executor.
+        for &(bench, function) in &[("spawn", "_start"), ("spin", "spin_process")] {
+            c.bench_function(&format!("lunatic process {} ({})", bench, name), |b| {
+                b.iter(|| {
+                    let process = Process::spawn(
+                        module.clone(),
+                        FunctionLookup::Name(function),
+                        MemoryChoice::New,
+                        ProcessConfig::default(),
+                    );
+                    future::block_on(EXECUTOR.run(process.join())).unwrap()
+                });
+            });
+        }
+    }
+}
+
+criterion_group!(benches, lunatic_bench, reduction_counting_bench);
 criterion_main!(benches);
@@ -0,0 +1,314 @@
+use super::{HttpClient, HttpRequest, HEAD_TIMEOUT};
+use crate::networking::{backend::NetworkBackend, policy::NetworkPolicy, TcpListener};
+use crate::wasi::types::*;
+use anyhow::Result;
+use uptown_funk::{host_functions, state::HashMapStore};
+
+use std::{
+    io::{self, IoSlice},
+    sync::Arc,
+};
+
+pub struct HttpState {
+    policy: Arc<NetworkPolicy>,
+    backend: Arc<dyn NetworkBackend>,
+    pub listeners: HashMapStore<TcpListener>,
+    pub requests: HashMapStore<HttpRequest>,
+    pub clients: HashMapStore<HttpClient>,
+}
+
+impl HttpState {
+    pub fn new(policy: Arc<NetworkPolicy>, backend: Arc<dyn NetworkBackend>) -> Self {
+        Self {
+            policy,
+            backend,
+            listeners: HashMapStore::new(),
+            requests: HashMapStore::new(),
+            clients: HashMapStore::new(),
+        }
+    }
+}
+
+#[host_functions(namespace = "http")]
+impl HttpState {
+    // All http functions return a WASI errno value as the first result. Malformed messages result
+    // in `WASI_EPROTO`. Functions writing strings into a guest `buffer` return the length of the
+    // string. If the buffer is too small, nothing is written, `WASI_ENOBUFS` is returned together
+    // with the required length.
+
+    // Server
+
+    async fn http_bind_str(&mut self, address: &str) -> (u32, u32) {
+        if !self.policy.is_enabled() {
+            return (WASI_ENOTCAPABLE, 0);
+        }
+        match TcpListener::bind(address, &self.policy, &*self.backend).await {
+            Ok(listener) => (WASI_ESUCCESS, self.listeners.add(listener)),
+            Err(err) => (errno(&err), 0),
+        }
+    }
+
+    fn http_listener_close(&mut self, listener_id: u32) -> u32 {
+        match self.listeners.remove(listener_id) {
+            Some(_listener) => WASI_ESUCCESS,
+            None => WASI_EBADF,
+        }
+    }
+
+    // Waits for the next connection and reads the request head from it. Connections that don't
+    // send a valid head in time are answered and skipped, see `HttpRequest::accept`.
+    async fn http_accept(&mut self, listener_id: u32) -> (u32, u32) {
+        let listener = match self.listeners.get(listener_id) {
+            Some(listener) => listener.clone(),
+            None => return (WASI_EBADF, 0),
+        };
+        match HttpRequest::accept(&listener, HEAD_TIMEOUT).await {
+            Ok(request) => (WASI_ESUCCESS, self.requests.add(request)),
+            Err(err) => (errno(&err), 0),
+        }
+    }
+
+    fn http_request_method(&self, request_id: u32, buffer: &mut [u8]) -> (u32, u32) {
+        match self.requests.get(request_id) {
+            Some(request) => write_buffer(request.method().as_bytes(), buffer),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    fn http_request_path(&self, request_id: u32, buffer: &mut [u8]) -> (u32, u32) {
+        match self.requests.get(request_id) {
+            Some(request) => write_buffer(request.path().as_bytes(), buffer),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Writes the value of the first header called `name` (case-insensitive) into `buffer`.
+    // Returns `WASI_ENOENT` if there is no such header.
+    fn http_request_header(&self, request_id: u32, name: &str, buffer: &mut [u8]) -> (u32, u32) {
+        match self.requests.get(request_id) {
+            Some(request) => match request.headers().get(name) {
+                Some(value) => write_buffer(value, buffer),
+                None => (WASI_ENOENT, 0),
+            },
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    fn http_request_header_count(&self, request_id: u32) -> (u32, u32) {
+        match self.requests.get(request_id) {
+            Some(request) => (WASI_ESUCCESS, request.headers().len() as u32),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Writes the header at `index` as "name: value" into `buffer`.
+    fn http_request_header_at(&self, request_id: u32, index: u32, buffer: &mut [u8]) -> (u32, u32) {
+        match self.requests.get(request_id) {
+            Some(request) => write_header(request.headers().get_index(index as usize), buffer),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Reads the next part of the request body into `buffer`. Returns 0 once the body was consumed.
+    async fn http_request_body_read(&mut self, request_id: u32, buffer: &mut [u8]) -> (u32, u32) {
+        match self.requests.get_mut(request_id) {
+            Some(request) => match request.read_body(buffer).await {
+                Ok(bytes_read) => (WASI_ESUCCESS, bytes_read as u32),
+                Err(err) => (errno(&err), 0),
+            },
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // The status and headers can only be changed until the first part of the body is written.
+    fn http_response_status(&mut self, request_id: u32, status: u32) -> u32 {
+        if !(100..1000).contains(&status) {
+            return WASI_EINVAL;
+        }
+        match self.requests.get_mut(request_id) {
+            Some(request) => result_to_errno(request.set_status(status as u16)),
+            None => WASI_EBADF,
+        }
+    }
+
+    fn http_response_header(&mut self, request_id: u32, name: &str, value: &str) -> u32 {
+        match self.requests.get_mut(request_id) {
+            Some(request) => result_to_errno(request.add_header(name, value)),
+            None => WASI_EBADF,
+        }
+    }
+
+    // Writes a part of the response body. If no `Content-Length` header was set, the body is
+    // sent with chunked transfer encoding.
+    async fn http_response_write<'a>(
+        &'a mut self,
+        request_id: u32,
+        ciovs: &'a [IoSlice<'a>],
+    ) -> (u32, u32) {
+        match self.requests.get_mut(request_id) {
+            Some(request) => match request.write_body(ciovs).await {
+                Ok(bytes_written) => (WASI_ESUCCESS, bytes_written as u32),
+                Err(err) => (errno(&err), 0),
+            },
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Finishes the response and closes the connection. The request is removed from the process.
+    async fn http_response_finish(&mut self, request_id: u32) -> u32 {
+        match self.requests.remove(request_id) {
+            Some(mut request) => result_to_errno(request.finish().await),
+            None => WASI_EBADF,
+        }
+    }
+
+    // Drops the request without finishing the response.
+    fn http_request_close(&mut self, request_id: u32) -> u32 {
+        match self.requests.remove(request_id) {
+            Some(_request) => WASI_ESUCCESS,
+            None => WASI_EBADF,
+        }
+    }
+
+    // Client
+
+    // Connects to the server in `url` and prepares a request. Only `http://` urls are supported,
+    // others fail with `WASI_ENOTSUP`.
+    async fn http_client_open(&mut self, method: &str, url: &str) -> (u32, u32) {
+        if !self.policy.is_enabled() {
+            return (WASI_ENOTCAPABLE, 0);
+        }
+        if !url.starts_with("http://") {
+            return (WASI_ENOTSUP, 0);
+        }
+        match HttpClient::connect(method, url, &self.policy, &*self.backend).await {
+            Ok(client) => (WASI_ESUCCESS, self.clients.add(client)),
+            Err(err) => (errno(&err), 0),
+        }
+    }
+
+    // Headers can only be added until the first part of the request body is written.
+    fn http_client_header(&mut self, client_id: u32, name: &str, value: &str) -> u32 {
+        match self.clients.get_mut(client_id) {
+            Some(client) => result_to_errno(client.add_header(name, value)),
+            None => WASI_EBADF,
+        }
+    }
+
+    async fn http_client_write<'a>(
+        &'a mut self,
+        client_id: u32,
+        ciovs: &'a [IoSlice<'a>],
+    ) -> (u32, u32) {
+        match self.clients.get_mut(client_id) {
+            Some(client) => match client.write_body(ciovs).await {
+                Ok(bytes_written) => (WASI_ESUCCESS, bytes_written as u32),
+                Err(err) => (errno(&err), 0),
+            },
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Finishes the request and waits for the response head. Returns the response status.
+    async fn http_client_send(&mut self, client_id: u32) -> (u32, u32) {
+        match self.clients.get_mut(client_id) {
+            Some(client) => match client.send().await {
+                Ok(status) => (WASI_ESUCCESS, status as u32),
+                Err(err) => (errno(&err), 0),
+            },
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Response headers are only available after `http_client_send`, before that `WASI_EINVAL`
+    // is returned.
+    fn http_client_response_header(
+        &self,
+        client_id: u32,
+        name: &str,
+        buffer: &mut [u8],
+    ) -> (u32, u32) {
+        match self.clients.get(client_id).map(HttpClient::headers) {
+            Some(Some(headers)) => match headers.get(name) {
+                Some(value) => write_buffer(value, buffer),
+                None => (WASI_ENOENT, 0),
+            },
+            Some(None) => (WASI_EINVAL, 0),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    fn http_client_response_header_count(&self, client_id: u32) -> (u32, u32) {
+        match self.clients.get(client_id).map(HttpClient::headers) {
+            Some(Some(headers)) => (WASI_ESUCCESS, headers.len() as u32),
+            Some(None) => (WASI_EINVAL, 0),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    fn http_client_response_header_at(
+        &self,
+        client_id: u32,
+        index: u32,
+        buffer: &mut [u8],
+    ) -> (u32, u32) {
+        match self.clients.get(client_id).map(HttpClient::headers) {
+            Some(Some(headers)) => write_header(headers.get_index(index as usize), buffer),
+            Some(None) => (WASI_EINVAL, 0),
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    // Reads the next part of the response body into `buffer`. Returns 0 once the body was consumed.
+    async fn http_client_body_read(&mut self, client_id: u32, buffer: &mut [u8]) -> (u32, u32) {
+        match self.clients.get_mut(client_id) {
+            Some(client) => match client.read_body(buffer).await {
+                Ok(bytes_read) => (WASI_ESUCCESS, bytes_read as u32),
+                Err(err) => (errno(&err), 0),
+            },
+            None => (WASI_EBADF, 0),
+        }
+    }
+
+    fn http_client_close(&mut self, client_id: u32) -> u32 {
+        match self.clients.remove(client_id) {
+            Some(_client) => WASI_ESUCCESS,
+            None => WASI_EBADF,
+        }
+    }
+}
+
+fn errno(error: &io::Error) -> u32 {
+    match error.kind() {
+        io::ErrorKind::InvalidData => WASI_EPROTO,
+        _ => io_error_to_errno(error),
+    }
+}
+
+fn result_to_errno(result: io::Result<()>) -> u32 {
+    match result {
+        Ok(()) => WASI_ESUCCESS,
+        Err(err) => errno(&err),
+    }
+}
+
+fn write_buffer(data: &[u8], buffer: &mut [u8]) -> (u32, u32) {
+    match buffer.get_mut(..data.len()) {
+        Some(destination) => {
+            destination.copy_from_slice(data);
+            (WASI_ESUCCESS, data.len() as u32)
+        }
+        None => (WASI_ENOBUFS, data.len() as u32),
+    }
+}
+
+fn write_header(header: Option<(&str, &[u8])>, buffer: &mut [u8]) -> (u32, u32) {
+    match header {
+        Some((name, value)) => {
+            let mut header = format!("{}: ", name).into_bytes();
+            header.extend_from_slice(value);
+            write_buffer(&header, buffer)
+        }
+        None => (WASI_ENOENT, 0),
+    }
+}
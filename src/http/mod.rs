@@ -0,0 +1,926 @@
+//! A minimal HTTP/1.1 server and client built on top of the networking resources.
+//!
+//! Request and response heads are parsed on the host, so that guests only need to deal with
+//! already parsed methods, paths and headers. Bodies are streamed in both directions, either with
+//! a known `Content-Length` or with chunked transfer encoding.
+//!
+//! Each connection carries exactly one request and is closed after the response is finished
+//! (`Connection: close`). Clients have `HEAD_TIMEOUT` to send the request head, connections with
+//! a slow or malformed head are answered with an error and skipped by `HttpRequest::accept`.
+
+pub mod api;
+
+use std::cmp;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::time::Duration;
+
+use crate::networking::{
+    backend::NetworkBackend, policy::NetworkPolicy, with_timeout, TcpListener, TcpStream,
+};
+
+// Upper limit for the size of request and response heads.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// Upper limit for the number of headers in a head.
+const MAX_HEADERS: usize = 128;
+// Upper limit for chunk size and trailer lines.
+const MAX_LINE_SIZE: usize = 8 * 1024;
+/// Time a client has to send the complete request head after connecting.
+pub const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Buffered reading and writing on top of a `TcpStream`.
+struct HttpStream {
+    stream: TcpStream,
+    // Data that was already read from the stream, but not yet consumed.
+    buffer: Vec<u8>,
+}
+
+impl HttpStream {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    // Reads more data from the stream into the buffer. Returns 0 at the end of the stream.
+    async fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; 4096];
+        let read = self
+            .stream
+            .read_vectored(&mut [IoSliceMut::new(&mut chunk)])
+            .await?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read)
+    }
+
+    // Reads until `delimiter` is found and returns everything before it.
+    async fn read_until(&mut self, delimiter: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = find(&self.buffer, delimiter) {
+                let data = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + delimiter.len());
+                return Ok(data);
+            }
+            if self.buffer.len() > limit {
+                return Err(protocol_error("HTTP head or line too large"));
+            }
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    async fn read_head(&mut self) -> io::Result<Vec<u8>> {
+        let mut head = self.read_until(b"\r\n\r\n", MAX_HEAD_SIZE).await?;
+        head.extend_from_slice(b"\r\n\r\n");
+        Ok(head)
+    }
+
+    async fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        self.read_until(b"\r\n", MAX_LINE_SIZE).await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.buffer.is_empty() && self.fill().await? == 0 {
+            return Ok(0);
+        }
+        let read = cmp::min(buf.len(), self.buffer.len());
+        buf[..read].copy_from_slice(&self.buffer[..read]);
+        self.buffer.drain(..read);
+        Ok(read)
+    }
+
+    async fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let written = self.stream.write_vectored(&[IoSlice::new(data)]).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Headers of a request or response. Names are compared case-insensitively.
+pub struct Headers(Vec<(String, Vec<u8>)>);
+
+impl Headers {
+    fn from_httparse(headers: &[httparse::Header]) -> Self {
+        Self(
+            headers
+                .iter()
+                .map(|header| (header.name.to_owned(), header.value.to_vec()))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn get_index(&self, index: usize) -> Option<(&str, &[u8])> {
+        self.0
+            .get(index)
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn is_chunked(&self) -> bool {
+        match self.get("transfer-encoding") {
+            Some(value) => String::from_utf8_lossy(value)
+                .split(',')
+                .any(|coding| coding.trim().eq_ignore_ascii_case("chunked")),
+            None => false,
+        }
+    }
+
+    fn content_length(&self) -> io::Result<Option<u64>> {
+        match self.get("content-length") {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .map(Some)
+                .ok_or_else(|| protocol_error("Invalid Content-Length header")),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Decodes a message body.
+enum BodyReader {
+    /// Bytes left to read.
+    Length(u64),
+    /// Bytes left in the current chunk and if the last chunk was read.
+    Chunked(u64, bool),
+    /// Responses without a length are terminated by closing the connection.
+    UntilClose,
+}
+
+impl BodyReader {
+    async fn read(&mut self, stream: &mut HttpStream, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BodyReader::Length(remaining) => {
+                let max = cmp::min(buf.len() as u64, *remaining) as usize;
+                if max == 0 {
+                    return Ok(0);
+                }
+                let read = stream.read(&mut buf[..max]).await?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                *remaining -= read as u64;
+                Ok(read)
+            }
+            BodyReader::Chunked(remaining, done) => {
+                if *done || buf.is_empty() {
+                    return Ok(0);
+                }
+                if *remaining == 0 {
+                    let line = stream.read_line().await?;
+                    let size = String::from_utf8_lossy(&line);
+                    // Ignore chunk extensions
+                    let size = size.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size, 16)
+                        .map_err(|_| protocol_error("Invalid chunk size"))?;
+                    if size == 0 {
+                        // Skip trailers
+                        while !stream.read_line().await?.is_empty() {}
+                        *done = true;
+                        return Ok(0);
+                    }
+                    *remaining = size;
+                }
+                let max = cmp::min(buf.len() as u64, *remaining) as usize;
+                let read = stream.read(&mut buf[..max]).await?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                *remaining -= read as u64;
+                if *remaining == 0 && !stream.read_line().await?.is_empty() {
+                    return Err(protocol_error("Missing CRLF after chunk"));
+                }
+                Ok(read)
+            }
+            BodyReader::UntilClose => stream.read(buf).await,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum WriterState {
+    Head,
+    Chunked,
+    /// Bytes left until the declared `Content-Length` is reached.
+    Fixed(u64),
+    Finished,
+}
+
+/// Encodes a message head and body. If no `Content-Length` header is set before the body is
+/// written, chunked transfer encoding is used. A declared `Content-Length` is enforced, writing
+/// more or finishing with less data fails with `io::ErrorKind::InvalidInput`.
+struct MessageWriter {
+    start_line: String,
+    headers: Vec<(String, String)>,
+    state: WriterState,
+    // Responses to `HEAD` requests have a head only, whatever their headers say.
+    has_body: bool,
+}
+
+impl MessageWriter {
+    fn new(start_line: String) -> Self {
+        Self {
+            start_line,
+            headers: Vec::new(),
+            state: WriterState::Head,
+            has_body: true,
+        }
+    }
+
+    fn set_start_line(&mut self, start_line: String) -> io::Result<()> {
+        if self.state != WriterState::Head {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Head was already sent",
+            ));
+        }
+        self.start_line = start_line;
+        Ok(())
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) -> io::Result<()> {
+        if self.state != WriterState::Head {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Head was already sent",
+            ));
+        }
+        // Don't allow guests to inject additional headers or break the head.
+        let invalid = |c: char| c == '\r' || c == '\n';
+        if name.is_empty()
+            || name.contains(invalid)
+            || name.contains(':')
+            || value.contains(invalid)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid header",
+            ));
+        }
+        if name.eq_ignore_ascii_case("content-length")
+            && (self.has_header(name) || value.trim().parse::<u64>().is_err())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid Content-Length header",
+            ));
+        }
+        self.headers.push((name.to_owned(), value.to_owned()));
+        Ok(())
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.header(name).is_some()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // `body_length` is only known if the head is sent while finishing the message.
+    async fn send_head(
+        &mut self,
+        stream: &mut HttpStream,
+        body_length: Option<u64>,
+    ) -> io::Result<()> {
+        let mut head = format!("{}\r\n", self.start_line);
+        // Validated when the header was added.
+        let declared_length = self
+            .header("content-length")
+            .map(|value| value.trim().parse().unwrap());
+        let state = if !self.has_body {
+            WriterState::Fixed(0)
+        } else if let Some(length) = declared_length {
+            WriterState::Fixed(length)
+        } else if let Some(length) = body_length {
+            head.push_str(&format!("Content-Length: {}\r\n", length));
+            WriterState::Fixed(length)
+        } else {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+            WriterState::Chunked
+        };
+        if !self.has_header("connection") {
+            head.push_str("Connection: close\r\n");
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        self.state = state;
+        Ok(())
+    }
+
+    async fn write(&mut self, stream: &mut HttpStream, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self.state {
+            WriterState::Head => self.send_head(stream, None).await?,
+            WriterState::Finished => return Err(io::ErrorKind::BrokenPipe.into()),
+            _ => {}
+        }
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if total == 0 {
+            return Ok(0);
+        }
+        if let WriterState::Fixed(remaining) = &mut self.state {
+            if total as u64 > *remaining {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Body is longer than the declared Content-Length",
+                ));
+            }
+            *remaining -= total as u64;
+        }
+        if self.state == WriterState::Chunked {
+            stream
+                .write_all(format!("{:x}\r\n", total).as_bytes())
+                .await?;
+        }
+        for buf in bufs {
+            stream.write_all(buf).await?;
+        }
+        if self.state == WriterState::Chunked {
+            stream.write_all(b"\r\n").await?;
+        }
+        Ok(total)
+    }
+
+    async fn finish(&mut self, stream: &mut HttpStream) -> io::Result<()> {
+        match self.state {
+            WriterState::Head => self.send_head(stream, Some(0)).await?,
+            WriterState::Chunked => stream.write_all(b"0\r\n\r\n").await?,
+            WriterState::Fixed(0) => {}
+            WriterState::Fixed(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Body is shorter than the declared Content-Length",
+                ))
+            }
+            WriterState::Finished => return Ok(()),
+        }
+        self.state = WriterState::Finished;
+        Ok(())
+    }
+}
+
+/// A request received by a server, together with the response that is written back.
+pub struct HttpRequest {
+    stream: HttpStream,
+    method: String,
+    path: String,
+    headers: Headers,
+    body: BodyReader,
+    response: MessageWriter,
+}
+
+impl HttpRequest {
+    /// Waits for the next connection that sends a valid request head within `head_timeout`.
+    /// Other connections are answered with `400 Bad Request` or `408 Request Timeout` and closed.
+    pub async fn accept(listener: &TcpListener, head_timeout: Duration) -> io::Result<Self> {
+        loop {
+            let stream = listener.accept().await?;
+            let mut error_stream = HttpStream::new(stream.clone());
+            let status = match with_timeout(Some(head_timeout), Self::read(stream)).await {
+                Ok(request) => return Ok(request),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => 408,
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => 400,
+                // The client went away.
+                Err(_) => continue,
+            };
+            // Best effort, the connection is dropped anyway.
+            let mut response = MessageWriter::new(status_line(status));
+            let _ = with_timeout(Some(head_timeout), async {
+                response.finish(&mut error_stream).await?;
+                error_stream.stream.shutdown(std::net::Shutdown::Both)
+            })
+            .await;
+        }
+    }
+
+    /// Reads and parses the request head from a freshly accepted connection.
+    pub async fn read(stream: TcpStream) -> io::Result<Self> {
+        let mut stream = HttpStream::new(stream);
+        let head = stream.read_head().await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            _ => return Err(protocol_error("Invalid HTTP request")),
+        };
+        let method = request.method.unwrap_or_default().to_owned();
+        let path = request.path.unwrap_or_default().to_owned();
+        let headers = Headers::from_httparse(request.headers);
+
+        let body = if headers.is_chunked() {
+            BodyReader::Chunked(0, false)
+        } else {
+            BodyReader::Length(headers.content_length()?.unwrap_or(0))
+        };
+
+        let mut response = MessageWriter::new(status_line(200));
+        response.has_body = !method.eq_ignore_ascii_case("HEAD");
+        Ok(Self {
+            stream,
+            method,
+            path,
+            headers,
+            body,
+            response,
+        })
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Reads the next part of the body. Returns 0 once the whole body was read.
+    pub async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(&mut self.stream, buf).await
+    }
+
+    /// Sets the response status. Must be called before the response body is written.
+    pub fn set_status(&mut self, status: u16) -> io::Result<()> {
+        self.response.set_start_line(status_line(status))
+    }
+
+    /// Adds a response header. Must be called before the response body is written.
+    pub fn add_header(&mut self, name: &str, value: &str) -> io::Result<()> {
+        self.response.add_header(name, value)
+    }
+
+    /// Writes a part of the response body, sending the head first if necessary.
+    pub async fn write_body(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.response.write(&mut self.stream, bufs).await
+    }
+
+    /// Finishes the response and closes the connection.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        self.response.finish(&mut self.stream).await?;
+        self.stream.stream.shutdown(std::net::Shutdown::Both)
+    }
+}
+
+fn status_line(status: u16) -> String {
+    let reason = match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    };
+    format!("HTTP/1.1 {} {}", status, reason)
+}
+
+/// Response received by the client.
+struct ClientResponse {
+    status: u16,
+    headers: Headers,
+    body: BodyReader,
+}
+
+/// An outgoing request. The request head and body are written first, then `send` finishes the
+/// request and waits for the response head.
+pub struct HttpClient {
+    stream: HttpStream,
+    method: String,
+    request: MessageWriter,
+    response: Option<ClientResponse>,
+}
+
+impl HttpClient {
+    /// Connects to the server in `url` (only the `http://` scheme is supported).
+    pub async fn connect(
+        method: &str,
+        url: &str,
+        policy: &NetworkPolicy,
+        backend: &dyn NetworkBackend,
+    ) -> io::Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Only the http:// scheme is supported",
+                ))
+            }
+        };
+        let (authority, path) = match rest.find(|c| c == '/' || c == '?') {
+            Some(index) if rest[index..].starts_with('/') => (&rest[..index], &rest[index..]),
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let path = if path.starts_with('?') {
+            format!("/{}", path)
+        } else {
+            path.to_owned()
+        };
+        if method.is_empty() || method.contains(|c: char| !c.is_ascii_alphabetic()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid HTTP method",
+            ));
+        }
+        if authority.is_empty() || path.contains(|c: char| c.is_ascii_whitespace()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid URL"));
+        }
+
+        // Add the default port if none is specified. IPv6 addresses are wrapped in `[]`.
+        let address = match authority.rfind(':') {
+            Some(colon) if !authority[colon..].contains(']') => authority.to_owned(),
+            _ => format!("{}:80", authority),
+        };
+        let stream = TcpStream::connect(&address, policy, backend).await?;
+
+        let mut request = MessageWriter::new(format!("{} {} HTTP/1.1", method, path));
+        request.add_header("Host", authority)?;
+        Ok(Self {
+            stream: HttpStream::new(stream),
+            method: method.to_owned(),
+            request,
+            response: None,
+        })
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) -> io::Result<()> {
+        self.request.add_header(name, value)
+    }
+
+    /// Writes a part of the request body, sending the head first if necessary.
+    pub async fn write_body(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.request.write(&mut self.stream, bufs).await
+    }
+
+    /// Finishes the request and reads the response head. Returns the response status.
+    pub async fn send(&mut self) -> io::Result<u16> {
+        self.request.finish(&mut self.stream).await?;
+
+        // Skip interim (1xx) responses.
+        let (status, headers) = loop {
+            let head = self.stream.read_head().await?;
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut response = httparse::Response::new(&mut headers);
+            match response.parse(&head) {
+                Ok(httparse::Status::Complete(_)) => {}
+                _ => return Err(protocol_error("Invalid HTTP response")),
+            };
+            let status = response.code.unwrap_or_default();
+            if status >= 200 || status == 101 {
+                break (status, Headers::from_httparse(response.headers));
+            }
+        };
+
+        let body = if self.method.eq_ignore_ascii_case("HEAD")
+            || status < 200
+            || status == 204
+            || status == 304
+        {
+            BodyReader::Length(0)
+        } else if headers.is_chunked() {
+            BodyReader::Chunked(0, false)
+        } else {
+            match headers.content_length()? {
+                Some(length) => BodyReader::Length(length),
+                None => BodyReader::UntilClose,
+            }
+        };
+
+        self.response = Some(ClientResponse {
+            status,
+            headers,
+            body,
+        });
+        Ok(status)
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.response.as_ref().map(|response| response.status)
+    }
+
+    pub fn headers(&self) -> Option<&Headers> {
+        self.response.as_ref().map(|response| &response.headers)
+    }
+
+    /// Reads the next part of the response body. Returns 0 once the whole body was read.
+    pub async fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.response.as_mut() {
+            Some(response) => response.body.read(&mut self.stream, buf).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Request was not sent yet",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::future;
+
+    use super::*;
+    use crate::networking::virtual_network::VirtualNetwork;
+
+    struct Connections {
+        network: VirtualNetwork,
+        policy: NetworkPolicy,
+        listener: TcpListener,
+    }
+
+    impl Connections {
+        fn new() -> Self {
+            let network = VirtualNetwork::new();
+            let policy = NetworkPolicy::allow_all();
+            let listener =
+                future::block_on(TcpListener::bind("127.0.0.1:0", &policy, &network)).unwrap();
+            Self {
+                network,
+                policy,
+                listener,
+            }
+        }
+
+        fn url(&self) -> String {
+            format!("http://{}/", self.listener.local_addr().unwrap())
+        }
+
+        // Connects a raw client to the listener.
+        fn connect(&self) -> HttpStream {
+            let address = self.listener.local_addr().unwrap().to_string();
+            let stream =
+                future::block_on(TcpStream::connect(&address, &self.policy, &self.network));
+            HttpStream::new(stream.unwrap())
+        }
+
+        // Returns a raw client and the request it sent.
+        fn request(&self, data: &[u8]) -> (HttpStream, io::Result<HttpRequest>) {
+            let mut client = self.connect();
+            future::block_on(client.write_all(data)).unwrap();
+            let server = future::block_on(self.listener.accept()).unwrap();
+            (client, future::block_on(HttpRequest::read(server)))
+        }
+    }
+
+    async fn read_to_end(stream: &mut HttpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return data,
+                read => data.extend_from_slice(&buf[..read]),
+            }
+        }
+    }
+
+    async fn read_body(request: &mut HttpRequest) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match request.read_body(&mut buf).await? {
+                0 => return Ok(body),
+                read => body.extend_from_slice(&buf[..read]),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_request_head() {
+        let connections = Connections::new();
+        let (_client, request) = connections
+            .request(b"GET /path?q=1 HTTP/1.1\r\nHost: example.com\r\nX-Test: a\r\n\r\n");
+        let mut request = request.unwrap();
+        assert_eq!(request.method(), "GET");
+        assert_eq!(request.path(), "/path?q=1");
+        assert_eq!(request.headers().len(), 2);
+        assert_eq!(request.headers().get("x-test"), Some(&b"a"[..]));
+        assert_eq!(
+            request.headers().get_index(0),
+            Some(("Host", &b"example.com"[..]))
+        );
+        assert_eq!(request.headers().get("missing"), None);
+        assert!(future::block_on(read_body(&mut request))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_request_heads() {
+        let connections = Connections::new();
+        let (_client, request) = connections.request(b"NOT HTTP\r\n\r\n");
+        assert_eq!(request.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut too_large = b"GET / HTTP/1.1\r\nX-Large: ".to_vec();
+        too_large.resize(MAX_HEAD_SIZE + 2, b'a');
+        let (_client, request) = connections.request(&too_large);
+        assert_eq!(request.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let (_client, request) =
+            connections.request(b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n");
+        assert_eq!(request.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_bodies() {
+        let connections = Connections::new();
+        let (_client, request) =
+            connections.request(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloextra");
+        let body = future::block_on(read_body(&mut request.unwrap())).unwrap();
+        assert_eq!(body, b"hello");
+
+        let (_client, request) = connections.request(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nTrailer: x\r\n\r\n",
+        );
+        let body = future::block_on(read_body(&mut request.unwrap())).unwrap();
+        assert_eq!(body, b"Wikipedia");
+
+        let (_client, request) = connections
+            .request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nWiki\r\n");
+        let err = future::block_on(read_body(&mut request.unwrap())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut long_line = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        long_line.resize(long_line.len() + MAX_LINE_SIZE + 2, b'0');
+        let (_client, request) = connections.request(&long_line);
+        let err = future::block_on(read_body(&mut request.unwrap())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chunked_without_content_length() {
+        let connections = Connections::new();
+        let (mut client, request) = connections.request(b"GET / HTTP/1.1\r\n\r\n");
+        let mut request = request.unwrap();
+        future::block_on(async {
+            request.write_body(&[IoSlice::new(b"Hello")]).await.unwrap();
+            request
+                .write_body(&[IoSlice::new(b", world")])
+                .await
+                .unwrap();
+            request.finish().await.unwrap();
+        });
+        let response = future::block_on(read_to_end(&mut client));
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             5\r\nHello\r\n7\r\n, world\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn content_length_is_enforced() {
+        let connections = Connections::new();
+
+        // Finishing without a body sends the length.
+        let (mut client, request) = connections.request(b"GET / HTTP/1.1\r\n\r\n");
+        let mut request = request.unwrap();
+        request.set_status(404).unwrap();
+        future::block_on(request.finish()).unwrap();
+        let response = future::block_on(read_to_end(&mut client));
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+
+        let (mut client, request) = connections.request(b"GET / HTTP/1.1\r\n\r\n");
+        let mut request = request.unwrap();
+        request.add_header("Content-Length", "5").unwrap();
+        assert!(request.add_header("content-length", "5").is_err());
+        future::block_on(async {
+            let err = request.write_body(&[IoSlice::new(b"toolong")]).await;
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            request.write_body(&[IoSlice::new(b"hel")]).await.unwrap();
+            let err = request.finish().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            request.write_body(&[IoSlice::new(b"lo")]).await.unwrap();
+            request.finish().await.unwrap();
+        });
+        let response = future::block_on(read_to_end(&mut client));
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello"
+        );
+
+        let (_client, request) = connections.request(b"GET / HTTP/1.1\r\n\r\n");
+        let err = request.unwrap().add_header("Content-Length", "-1");
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn client_round_trip() {
+        let connections = Connections::new();
+        let server = async {
+            let stream = connections.listener.accept().await.unwrap();
+            let mut request = HttpRequest::read(stream).await.unwrap();
+            assert_eq!(read_body(&mut request).await.unwrap(), b"ping");
+            request.write_body(&[IoSlice::new(b"pong")]).await.unwrap();
+            request.finish().await.unwrap();
+        };
+        let client = async {
+            let mut client = HttpClient::connect(
+                "POST",
+                &connections.url(),
+                &connections.policy,
+                &connections.network,
+            )
+            .await
+            .unwrap();
+            client.write_body(&[IoSlice::new(b"ping")]).await.unwrap();
+            assert_eq!(client.send().await.unwrap(), 200);
+            let headers = client.headers().unwrap();
+            assert_eq!(headers.get("transfer-encoding"), Some(&b"chunked"[..]));
+            let mut body = [0; 8];
+            let read = client.read_body(&mut body).await.unwrap();
+            assert_eq!(&body[..read], b"pong");
+            assert_eq!(client.read_body(&mut body).await.unwrap(), 0);
+        };
+        future::block_on(future::zip(server, client));
+    }
+
+    #[test]
+    fn accept_skips_bad_connections() {
+        let connections = Connections::new();
+        let mut malformed = connections.connect();
+        future::block_on(malformed.write_all(b"NOT HTTP\r\n\r\n")).unwrap();
+        let mut slow = connections.connect();
+        future::block_on(slow.write_all(b"GET / HTTP/1.1\r\n")).unwrap();
+        let mut valid = connections.connect();
+        future::block_on(valid.write_all(b"GET /valid HTTP/1.1\r\n\r\n")).unwrap();
+
+        let accept = HttpRequest::accept(&connections.listener, Duration::from_millis(50));
+        let request = future::block_on(accept).unwrap();
+        assert_eq!(request.path(), "/valid");
+
+        let response = future::block_on(read_to_end(&mut malformed));
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+        let response = future::block_on(read_to_end(&mut slow));
+        assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+    }
+}
@@ -1,6 +1,7 @@
 #![feature(available_concurrency)]
 
 pub mod channel;
+pub mod http;
 pub mod linker;
 pub mod memory;
 pub mod module;
@@ -9,41 +10,200 @@ pub mod normalisation;
 pub mod process;
 pub mod wasi;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use easy_parallel::Parallel;
 
-use process::{FunctionLookup, MemoryChoice, Process, EXECUTOR};
+use networking::{policy::NetworkPolicy, TcpListener};
+use normalisation::{CostModel, Pipeline};
+use process::{FunctionLookup, MemoryChoice, Process, ProcessConfig, ProcessOutcome, EXECUTOR};
+use wasi::{stdio::Output, vfs::VirtualDir};
 
 use std::env;
 use std::fs;
+use std::sync::Arc;
 use std::thread;
 
-pub fn run() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let wasm_path = args.get(1).expect("Not enough arguments passed");
+/// Runs the module passed on the command line and returns how its `_start` process finished.
+pub fn run() -> Result<ProcessOutcome> {
+    let (wasm_path, pipeline, config) = parse_arguments(env::args().skip(1))?;
     let wasm = fs::read(wasm_path).expect("Can't open WASM file");
 
-    let module = module::LunaticModule::new(wasm)?;
+    let module = module::LunaticModule::with_pipeline(wasm, &pipeline)?;
 
     // Set up async runtime
     let cpus = thread::available_concurrency().unwrap();
     let (signal, shutdown) = smol::channel::unbounded::<()>();
 
-    Parallel::new()
+    let (_, outcome) = Parallel::new()
         .each(0..cpus.into(), |_| {
             smol::future::block_on(EXECUTOR.run(shutdown.recv()))
         })
         .finish(|| {
             smol::future::block_on(async {
-                let result =
-                    Process::spawn(module, FunctionLookup::Name("_start"), MemoryChoice::New)
-                        .join()
-                        .await;
+                let result = Process::spawn(
+                    module,
+                    FunctionLookup::Name("_start"),
+                    MemoryChoice::New,
+                    config,
+                )
+                .join()
+                .await;
                 drop(signal);
                 result
             })
-        })
-        .1?;
+        });
+
+    outcome
+}
+
+// Parses the command line arguments `[OPTIONS] [--] <module.wasm> [ARGS]...`.
+//
+// The module path and all arguments following it are passed to the guest as its arguments. `--`
+// can be used to end the options, in case the module path starts with `--`.
+//
+// Options:
+// * `--net-allow <rule>` - Only allow access to addresses matching one of the allow rules
+// * `--net-deny <rule>` - Deny access to addresses matching the rule
+// * `--no-net` - Disable networking
+// * `--tcp-listen <address>` - Bind a listener and pass it to the guest as a preopened WASI socket
+// * `--dir <host[::guest]>` - Give the guest access to a host directory, optionally under another path
+// * `--vdir <source[::guest]>` - Give the guest an in-memory directory, see below
+// * `--vdir-cow <source[::guest]>` - Like `--vdir`, but children start with a copy of their parent's
+//   directory instead of the original contents
+// * `--env <KEY=VALUE>` - Set an environment variable of the guest
+// * `--inherit-env` - Pass the host's environment variables to the guest, `--env` takes precedence
+// * `--stdout <output>` - Redirect the stdout of all processes, see below
+// * `--stderr <output>` - Redirect the stderr of all processes, see below
+// * `--reduction-limit <count>` - How many reductions processes do before yielding (default 10000)
+// * `--cost-model <model>` - `uniform` counts each function call and loop iteration as one
+//   reduction (the default), `weighted` counts the instructions of each block when it is entered
+// * `--patches <names>` - Comma separated list of the normalisation patches applied to the module,
+//   in order (default `reduction_counting,stdlib,shared_memory`)
+// * `--no-patch <name>` - Don't apply one of the normalisation patches. `shared_memory` is required
+//   for modules with a memory
+//
+// An output is `inherit` (the default), `prefix` to prefix each line with the process id, `null`
+// to discard it or the path of a file to append to.
+//
+// The source of a virtual directory is a host directory or a `.tar` archive that is copied into
+// memory. It can be left empty to start with an empty directory. Changes never reach the host.
+//
+// See `networking::policy::NetworkRule` for the syntax of rules.
+fn parse_arguments(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(String, Pipeline, ProcessConfig)> {
+    let mut network_policy = NetworkPolicy::allow_all();
+    let mut tcp_listen = Vec::new();
+    let mut preopened_dirs = Vec::new();
+    let mut virtual_dirs = Vec::new();
+    let mut env = Vec::new();
+    let mut inherit_env = false;
+    let mut stdout = Output::Inherit;
+    let mut stderr = Output::Inherit;
+    let mut reduction_limit = normalisation::DEFAULT_REDUCTION_LIMIT;
+    let mut cost_model = CostModel::default();
+    let mut patches = None;
+    let mut disabled_patches = Vec::new();
+
+    let wasm_path = loop {
+        let arg = args
+            .next()
+            .ok_or_else(|| anyhow!("Not enough arguments passed"))?;
+        match arg.as_str() {
+            "--net-allow" => network_policy.allow(option_value(&mut args, &arg)?.parse()?),
+            "--net-deny" => network_policy.deny(option_value(&mut args, &arg)?.parse()?),
+            "--no-net" => network_policy = NetworkPolicy::disabled(),
+            "--tcp-listen" => tcp_listen.push(option_value(&mut args, &arg)?),
+            "--dir" => preopened_dirs.push(option_value(&mut args, &arg)?.parse()?),
+            "--vdir" => virtual_dirs.push(option_value(&mut args, &arg)?.parse()?),
+            "--vdir-cow" => virtual_dirs.push(
+                option_value(&mut args, &arg)?
+                    .parse::<VirtualDir>()?
+                    .copy_on_write(),
+            ),
+            "--env" => env.push(env_var(&option_value(&mut args, &arg)?)?),
+            "--inherit-env" => inherit_env = true,
+            "--stdout" => stdout = option_value(&mut args, &arg)?.parse()?,
+            "--stderr" => stderr = option_value(&mut args, &arg)?.parse()?,
+            "--reduction-limit" => reduction_limit = reduction_limit_value(&mut args, &arg)?,
+            "--cost-model" => cost_model = option_value(&mut args, &arg)?.parse()?,
+            "--patches" => patches = Some(option_value(&mut args, &arg)?),
+            "--no-patch" => disabled_patches.push(option_value(&mut args, &arg)?),
+            "--" => {
+                break args
+                    .next()
+                    .ok_or_else(|| anyhow!("Not enough arguments passed"))?
+            }
+            _ => break arg,
+        }
+    };
+
+    if inherit_env {
+        let explicit = env;
+        env = env::vars()
+            .filter(|(key, _)| !explicit.iter().any(|(explicit, _)| explicit == key))
+            .collect();
+        env.extend(explicit);
+    }
+
+    let mut config = ProcessConfig {
+        args: std::iter::once(wasm_path.clone()).chain(args).collect(),
+        network_policy: Arc::new(network_policy),
+        env,
+        preopened_dirs,
+        virtual_dirs,
+        stdout,
+        stderr,
+        reduction_limit,
+        ..ProcessConfig::default()
+    };
+    // Preopened sockets are subject to the network policy too.
+    for address in tcp_listen {
+        let listener = smol::future::block_on(TcpListener::bind(
+            &address,
+            &config.network_policy,
+            &*config.network_backend,
+        ))
+        .map_err(|err| anyhow!("Can't listen on `{}`: {}", address, err))?;
+        config.preopened_sockets.push(listener);
+    }
+
+    let mut pipeline = match patches {
+        Some(names) => {
+            let names: Vec<&str> = names.split(',').filter(|name| !name.is_empty()).collect();
+            Pipeline::from_names(&names, cost_model)?
+        }
+        None => Pipeline::with_cost_model(cost_model),
+    };
+    for name in disabled_patches {
+        pipeline = pipeline.without(&name)?;
+    }
+    Ok((wasm_path, pipeline, config))
+}
+
+fn option_value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("Missing value for option `{}`", option))
+}
+
+fn env_var(var: &str) -> Result<(String, String)> {
+    let mut parts = var.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(anyhow!(
+            "Invalid environment variable `{}`, expected `KEY=VALUE`",
+            var
+        )),
+    }
+}
 
-    Ok(())
+fn reduction_limit_value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<u32> {
+    let value = option_value(args, option)?;
+    match value.parse() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(anyhow!(
+            "Invalid reduction limit `{}`, expected a positive integer",
+            value
+        )),
+    }
 }
@@ -3,6 +3,10 @@ mod wasmtime;
 #[cfg(feature = "vm-wasmtime")]
 pub use self::wasmtime::*;
 
+// The Wasmer linker doesn't set up the per process state (`ProcessConfig`, WASI, networking)
+// yet, fail early instead of with errors from deep inside of it.
+#[cfg(feature = "vm-wasmer")]
+compile_error!("The `vm-wasmer` backend is not supported yet, use `vm-wasmtime`");
 #[cfg(feature = "vm-wasmer")]
 mod wasmer;
 #[cfg(feature = "vm-wasmer")]
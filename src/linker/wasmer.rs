@@ -4,12 +4,15 @@ pub struct LunaticLinker {}
 
 impl LunaticLinker {
     /// Create a new LunaticLinker.
+    // Same signature as the Wasmtime linker, `process_id` and `config` are not used yet.
     pub fn new(
-        engine: Engine,
-        module: Module,
+        module: LunaticModule,
+        _process_id: u64,
         yielder_ptr: usize,
         memory: MemoryChoice,
+        _config: ProcessConfig,
     ) -> Result<Self> {
+        let engine = engine();
         let store = Store::new(&engine);
         let mut linker = Linker::new(&store);
 
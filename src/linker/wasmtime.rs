@@ -1,15 +1,22 @@
 use crate::channel;
+use crate::http;
 use crate::memory::LunaticMemory;
 use crate::module::LunaticModule;
 use crate::networking;
-use crate::process::{self, MemoryChoice, ProcessEnvironment};
+use crate::normalisation::Pipeline;
+use crate::process::{self, MemoryChoice, ProcessConfig, ProcessEnvironment};
 use crate::wasi;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::mem::ManuallyDrop;
 use std::sync::Once;
 use uptown_funk::HostFunctions;
-use wasmtime::{Config, Engine, Instance, Limits, Linker, Memory, MemoryType, Store};
+use wasmtime::{
+    Config, Engine, ExternType, FuncType, Global, GlobalType, Instance, Limits, Linker, Memory,
+    MemoryType, Module, Mutability, Store, Val, ValType,
+};
 
 /// Contains data necessary to create Wasmtime instances suitable to be used with Lunatic processes.
 /// Lunatic's instances have their own store, linker and process environment associated with them.
@@ -20,7 +27,13 @@ pub struct LunaticLinker {
 
 impl LunaticLinker {
     /// Create a new LunaticLinker.
-    pub fn new(module: LunaticModule, yielder_ptr: usize, memory: MemoryChoice) -> Result<Self> {
+    pub fn new(
+        module: LunaticModule,
+        process_id: u64,
+        yielder_ptr: usize,
+        memory: MemoryChoice,
+        config: ProcessConfig,
+    ) -> Result<Self> {
         let engine = engine();
         let store = Store::new(&engine);
         let mut linker = Linker::new(&store);
@@ -46,17 +59,36 @@ impl LunaticLinker {
 
         linker.define("lunatic", "memory", memory_duplicate)?;
 
-        let process_state = process::api::ProcessState::new(module.clone());
+        // Compared as an unsigned integer by the reduction counting code.
+        let reduction_limit = Global::new(
+            &store,
+            GlobalType::new(ValType::I32, Mutability::Const),
+            Val::I32(config.reduction_limit as i32),
+        )?;
+        linker.define("lunatic", "reduction_limit", reduction_limit)?;
+
+        let process_state = process::api::ProcessState::new(module.clone(), config.clone());
         process_state.add_to_linker(environment.clone(), &mut linker);
 
         let channel_state = channel::api::ChannelState::new();
         channel_state.add_to_linker(environment.clone(), &mut linker);
 
-        let networking_state = networking::api::TcpState::new();
+        let networking_state = networking::api::TcpState::new(
+            config.network_policy.clone(),
+            config.network_backend.clone(),
+        );
         networking_state.add_to_linker(environment.clone(), &mut linker);
 
-        let wasi_state = wasi::api::WasiState::new();
-        wasi_state.add_to_linker(environment, &mut linker);
+        let http_state = http::api::HttpState::new(
+            config.network_policy.clone(),
+            config.network_backend.clone(),
+        );
+        http_state.add_to_linker(environment.clone(), &mut linker);
+
+        let wasi_state = wasi::api::WasiState::new(&config, process_id);
+        let wasi_unstable_state = wasi::unstable::WasiUnstableState::new(wasi_state.share());
+        wasi_state.add_to_linker(environment.clone(), &mut linker);
+        wasi_unstable_state.add_to_linker(environment, &mut linker);
 
         Ok(Self { linker, module })
     }
@@ -69,7 +101,71 @@ impl LunaticLinker {
     }
 }
 
+lazy_static! {
+    // The types of everything the host defines, by namespace and name. They are the same for all
+    // processes, so they are collected once from the linker of an empty module.
+    static ref HOST_IMPORTS: HashMap<(String, String), ExternType> = {
+        let empty = LunaticModule::compile(b"\0asm\x01\0\0\0".to_vec(), &Pipeline::empty())
+            .expect("Can't compile an empty module");
+        // The linker is never used to run anything, so it doesn't need a yielder.
+        let linker = LunaticLinker::new(empty, 0, 0, MemoryChoice::New, ProcessConfig::default())
+            .expect("Can't create a linker for an empty module");
+        linker
+            .linker
+            .iter()
+            .map(|(module, name, definition)| ((module.to_owned(), name.to_owned()), definition.ty()))
+            .collect()
+    };
+}
+
+/// Checks that the host provides every import of the module. All missing imports and signature
+/// mismatches are reported together in one error.
+pub fn check_imports(module: &Module) -> Result<()> {
+    let mut problems = Vec::new();
+    for import in module.imports() {
+        let key = (import.module().to_owned(), import.name().to_owned());
+        let problem = match (import.ty(), HOST_IMPORTS.get(&key)) {
+            (_, None) => "not provided by the host".to_owned(),
+            // Each process gets a memory matching the limits of the module.
+            (ExternType::Memory(_), Some(ExternType::Memory(_))) => continue,
+            (expected, Some(provided)) if expected == *provided => continue,
+            (ExternType::Func(expected), Some(ExternType::Func(provided))) => format!(
+                "the module expects the signature {}, but the host function is {}",
+                signature(&expected),
+                signature(provided)
+            ),
+            (expected, Some(provided)) => format!(
+                "the module expects {}, but the host provides {}",
+                extern_kind(&expected),
+                extern_kind(provided)
+            ),
+        };
+        problems.push(format!(
+            "  {}::{}: {}",
+            import.module(),
+            import.name(),
+            problem
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "The module has {} unsatisfied import(s):\n{}",
+            problems.len(),
+            problems.join("\n")
+        ))
+    }
+}
+
 /// Return a configured Wasmtime engine.
+///
+/// Processes are preempted by the reduction counting patched into each module (see
+/// `normalisation`), not by the engine. A fuel or epoch based scheduling mode is deferred until
+/// Wasmtime is upgraded: 0.21 has neither fuel metering nor epoch interruption, and an interrupt
+/// (`Config::interruptable`) traps instead of letting the process continue after yielding. The
+/// `reduction_counting` benchmarks measure what such a mode would need to beat.
 pub fn engine() -> Engine {
     static mut ENGINE: Option<Engine> = None;
     static INIT: Once = Once::new();
@@ -85,3 +181,27 @@ pub fn engine() -> Engine {
         ENGINE.clone().unwrap()
     }
 }
+
+// Formats the signature like `(i32, i32) -> (i32)`.
+fn signature(ty: &FuncType) -> String {
+    let types = |types: &mut dyn Iterator<Item = ValType>| {
+        types
+            .map(|ty| ty.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    format!(
+        "({}) -> ({})",
+        types(&mut ty.params()),
+        types(&mut ty.results())
+    )
+}
+
+fn extern_kind(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Func(ty) => format!("a function {}", signature(ty)),
+        ExternType::Global(ty) => format!("a global of type {}", ty.content()),
+        ExternType::Memory(_) => "a memory".to_owned(),
+        ExternType::Table(_) => "a table".to_owned(),
+    }
+}
@@ -1,6 +1,10 @@
 use anyhow::Result;
+use lunatic_vm::process::ProcessOutcome;
 
 fn main() -> Result<()> {
     env_logger::init();
-    lunatic_vm::run()
+    if let ProcessOutcome::Exited(exit_code) = lunatic_vm::run()? {
+        std::process::exit(exit_code);
+    }
+    Ok(())
 }
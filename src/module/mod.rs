@@ -1,8 +1,8 @@
 use anyhow::Result;
 use wasmtime::Module;
 
-use crate::linker::engine;
-use crate::normalisation::patch;
+use crate::linker::{check_imports, engine};
+use crate::normalisation::Pipeline;
 
 #[derive(Clone)]
 pub struct LunaticModule {
@@ -12,9 +12,24 @@ pub struct LunaticModule {
 }
 
 impl LunaticModule {
+    /// Compiles the module after applying the default normalisation pipeline. Fails if the module
+    /// imports anything the host doesn't provide.
     pub fn new(wasm: Vec<u8>) -> Result<Self> {
+        Self::with_pipeline(wasm, &Pipeline::default())
+    }
+
+    /// Like `new`, but applies the normalisation `pipeline` instead of the default one.
+    pub fn with_pipeline(wasm: Vec<u8>, pipeline: &Pipeline) -> Result<Self> {
+        let module = Self::compile(wasm, pipeline)?;
+        // Otherwise unsatisfied imports are only discovered when the first process is spawned.
+        check_imports(&module.module)?;
+        Ok(module)
+    }
+
+    // Compiles the module without checking its imports.
+    pub(crate) fn compile(wasm: Vec<u8>, pipeline: &Pipeline) -> Result<Self> {
         // Transfrom WASM file into a format compatible with Lunatic.
-        let ((min_memory, max_memory), wasm) = patch(&wasm)?;
+        let ((min_memory, max_memory), wasm) = pipeline.apply(&wasm)?;
 
         let engine = engine();
         let module = Module::new(&engine, wasm)?;
@@ -38,3 +53,45 @@ impl LunaticModule {
         self.max_memory
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsatisfied_imports() {
+        let wasm = wat::parse_str(
+            r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+              (import "lunatic" "missing" (func))
+              (import "wasi_snapshot_preview1" "proc_exit" (func (param i64)))
+              (memory 1))
+            "#,
+        )
+        .unwrap();
+        let error = LunaticModule::new(wasm).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "The module has 2 unsatisfied import(s):\n\
+             \x20 lunatic::missing: not provided by the host\n\
+             \x20 wasi_snapshot_preview1::proc_exit: the module expects the signature (i64) -> (), \
+             but the host function is (i32) -> ()"
+        );
+    }
+
+    #[test]
+    fn satisfied_imports() {
+        let wasm = wat::parse_str(
+            r#"
+            (module
+              (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+              (import "wasi_unstable" "proc_exit" (func (param i32)))
+              (memory 1 2))
+            "#,
+        )
+        .unwrap();
+        let module = LunaticModule::new(wasm).unwrap();
+        assert_eq!((module.min_memory(), module.max_memory()), (1, Some(2)));
+    }
+}
@@ -1,40 +1,92 @@
-use super::{TcpListener, TcpListenerResult, TcpStream, TcpStreamResult};
+use super::{
+    backend::NetworkBackend, policy::NetworkPolicy, TcpListener, TcpListenerResult, TcpStream,
+    TcpStreamResult,
+};
+use crate::wasi::types::*;
 use anyhow::Result;
-use smol::prelude::*;
 use uptown_funk::{host_functions, state::HashMapStore};
 
 use std::{
-    io::{IoSlice, IoSliceMut},
-    sync::atomic::Ordering,
+    io::{self, IoSlice, IoSliceMut},
+    net::{Shutdown, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
 
 pub struct TcpState {
+    policy: Arc<NetworkPolicy>,
+    backend: Arc<dyn NetworkBackend>,
     pub listeners: HashMapStore<TcpListener>,
     pub streams: HashMapStore<TcpStream>,
 }
 
 impl TcpState {
-    pub fn new() -> Self {
+    pub fn new(policy: Arc<NetworkPolicy>, backend: Arc<dyn NetworkBackend>) -> Self {
         Self {
+            policy,
+            backend,
             listeners: HashMapStore::new(),
             streams: HashMapStore::new(),
         }
     }
+
+    fn not_capable() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "Networking is disabled for this process",
+        )
+    }
 }
 
 #[host_functions(namespace = "lunatic")]
 impl TcpState {
+    // All networking functions return a WASI errno value as the first result.
+
     async fn tcp_bind_str(&self, address: &str) -> (u32, TcpListenerResult) {
-        match TcpListener::bind(address).await {
-            Ok(listener) => (0, TcpListenerResult::Ok(listener)),
-            Err(err) => (1, TcpListenerResult::Err(err)),
+        if !self.policy.is_enabled() {
+            return (
+                WASI_ENOTCAPABLE,
+                TcpListenerResult::Err(Self::not_capable()),
+            );
+        }
+        match TcpListener::bind(address, &self.policy, &*self.backend).await {
+            Ok(listener) => (WASI_ESUCCESS, TcpListenerResult::Ok(listener)),
+            Err(err) => (io_error_to_errno(&err), TcpListenerResult::Err(err)),
+        }
+    }
+
+    async fn tcp_connect_str(&self, address: &str) -> (u32, TcpStreamResult) {
+        if !self.policy.is_enabled() {
+            return (WASI_ENOTCAPABLE, TcpStreamResult::Err(Self::not_capable()));
+        }
+        match TcpStream::connect(address, &self.policy, &*self.backend).await {
+            Ok(stream) => (WASI_ESUCCESS, TcpStreamResult::Ok(stream)),
+            Err(err) => (io_error_to_errno(&err), TcpStreamResult::Err(err)),
         }
     }
 
     async fn tcp_accept(&self, tcp_listener: TcpListener) -> (u32, TcpStreamResult) {
         match tcp_listener.accept().await {
-            Ok(stream) => (0, TcpStreamResult::Ok(stream)),
-            Err(err) => (1, TcpStreamResult::Err(err)),
+            Ok(stream) => (WASI_ESUCCESS, TcpStreamResult::Ok(stream)),
+            Err(err) => (io_error_to_errno(&err), TcpStreamResult::Err(err)),
+        }
+    }
+
+    // Removes the listener from this process. The socket is closed once no other process holds
+    // a serialized reference to it.
+    fn tcp_listener_close(&mut self, tcp_listener_id: u32) -> u32 {
+        match self.listeners.remove(tcp_listener_id) {
+            Some(_listener) => WASI_ESUCCESS,
+            None => WASI_EBADF,
+        }
+    }
+
+    // Removes the stream from this process. The socket is closed once no other process holds
+    // a serialized reference to it.
+    fn tcp_stream_close(&mut self, tcp_stream_id: u32) -> u32 {
+        match self.streams.remove(tcp_stream_id) {
+            Some(_stream) => WASI_ESUCCESS,
+            None => WASI_EBADF,
         }
     }
 
@@ -43,9 +95,9 @@ impl TcpState {
         mut tcp_stream: TcpStream,
         ciovs: &[IoSlice<'_>],
     ) -> (u32, u32) {
-        match tcp_stream.stream.write_vectored(ciovs).await {
-            Ok(bytes_written) => (0, bytes_written as u32),
-            Err(_) => (1, 0),
+        match tcp_stream.write_vectored(ciovs).await {
+            Ok(bytes_written) => (WASI_ESUCCESS, bytes_written as u32),
+            Err(err) => (io_error_to_errno(&err), 0),
         }
     }
 
@@ -54,12 +106,97 @@ impl TcpState {
         tcp_stream: &'a mut TcpStream,
         iovs: &'a mut [IoSliceMut<'a>],
     ) -> (u32, u32) {
-        match tcp_stream.stream.read_vectored(iovs).await {
-            Ok(bytes_written) => (0, bytes_written as u32),
-            Err(_) => (1, 0),
+        match tcp_stream.read_vectored(iovs).await {
+            Ok(bytes_read) => (WASI_ESUCCESS, bytes_read as u32),
+            Err(err) => (io_error_to_errno(&err), 0),
+        }
+    }
+
+    // Same as `tcp_read_vectored`, but fails with `WASI_ETIMEDOUT` if no data arrives in `timeout_ms`.
+    // A value of 0 waits forever, ignoring the read timeout of the stream.
+    async fn tcp_read_vectored_timeout<'a>(
+        &self,
+        tcp_stream: &'a mut TcpStream,
+        iovs: &'a mut [IoSliceMut<'a>],
+        timeout_ms: i64,
+    ) -> (u32, u32) {
+        match tcp_stream
+            .read_vectored_timeout(iovs, millis_to_timeout(timeout_ms))
+            .await
+        {
+            Ok(bytes_read) => (WASI_ESUCCESS, bytes_read as u32),
+            Err(err) => (io_error_to_errno(&err), 0),
+        }
+    }
+
+    // Sets the timeout for all following reads from this stream. A value of 0 disables it.
+    fn tcp_set_read_timeout(&mut self, tcp_stream_id: u32, timeout_ms: i64) -> u32 {
+        match self.streams.get_mut(tcp_stream_id) {
+            Some(tcp_stream) => {
+                tcp_stream.set_read_timeout(millis_to_timeout(timeout_ms));
+                WASI_ESUCCESS
+            }
+            None => WASI_EBADF,
+        }
+    }
+
+    // Sets the timeout for all following writes to this stream. A value of 0 disables it.
+    fn tcp_set_write_timeout(&mut self, tcp_stream_id: u32, timeout_ms: i64) -> u32 {
+        match self.streams.get_mut(tcp_stream_id) {
+            Some(tcp_stream) => {
+                tcp_stream.set_write_timeout(millis_to_timeout(timeout_ms));
+                WASI_ESUCCESS
+            }
+            None => WASI_EBADF,
         }
     }
 
+    // Writes the local address of the listener as a string ("ip:port") into `buffer`.
+    // Returns the length of the address. If the buffer is too small, nothing is written, `WASI_ENOBUFS`
+    // is returned together with the required length.
+    fn tcp_listener_local_addr(&self, tcp_listener: TcpListener, buffer: &mut [u8]) -> (u32, u32) {
+        write_address(tcp_listener.local_addr(), buffer)
+    }
+
+    // Writes the local address of the stream as a string ("ip:port") into `buffer`.
+    fn tcp_local_addr(&self, tcp_stream: TcpStream, buffer: &mut [u8]) -> (u32, u32) {
+        write_address(tcp_stream.local_addr(), buffer)
+    }
+
+    // Writes the address of the remote peer as a string ("ip:port") into `buffer`.
+    fn tcp_peer_addr(&self, tcp_stream: TcpStream, buffer: &mut [u8]) -> (u32, u32) {
+        write_address(Ok(tcp_stream.peer_addr()), buffer)
+    }
+
+    fn tcp_set_nodelay(&self, tcp_stream: TcpStream, nodelay: u32) -> u32 {
+        status(tcp_stream.set_nodelay(nodelay != 0))
+    }
+
+    fn tcp_set_ttl(&self, tcp_stream: TcpStream, ttl: u32) -> u32 {
+        status(tcp_stream.set_ttl(ttl))
+    }
+
+    // Enables TCP keepalive with an idle time of `seconds`. Passing 0 disables it.
+    fn tcp_set_keepalive(&self, tcp_stream: TcpStream, seconds: i64) -> u32 {
+        let keepalive = if seconds > 0 {
+            Some(Duration::from_secs(seconds as u64))
+        } else {
+            None
+        };
+        status(tcp_stream.set_keepalive(keepalive))
+    }
+
+    // Shuts down the read (0), write (1) or both (2) halves of the connection.
+    fn tcp_shutdown(&self, tcp_stream: TcpStream, how: u32) -> u32 {
+        let how = match how {
+            0 => Shutdown::Read,
+            1 => Shutdown::Write,
+            2 => Shutdown::Both,
+            _ => return WASI_EINVAL,
+        };
+        status(tcp_stream.shutdown(how))
+    }
+
     // Serializes an Externref containing a tcp_stream as an id.
     // Memory leak: If the value in never deserialized, this will leak memory.
     async fn tcp_stream_serialize(&self, tcp_stream: TcpStream) -> i64 {
@@ -75,3 +212,33 @@ impl TcpState {
         }
     }
 }
+
+fn millis_to_timeout(millis: i64) -> Option<Duration> {
+    if millis > 0 {
+        Some(Duration::from_millis(millis as u64))
+    } else {
+        None
+    }
+}
+
+fn status(result: io::Result<()>) -> u32 {
+    match result {
+        Ok(()) => WASI_ESUCCESS,
+        Err(err) => io_error_to_errno(&err),
+    }
+}
+
+fn write_address(address: io::Result<SocketAddr>, buffer: &mut [u8]) -> (u32, u32) {
+    let address = match address {
+        Ok(address) => address.to_string(),
+        Err(err) => return (io_error_to_errno(&err), 0),
+    };
+    let address = address.as_bytes();
+    match buffer.get_mut(..address.len()) {
+        Some(destination) => {
+            destination.copy_from_slice(address);
+            (WASI_ESUCCESS, address.len() as u32)
+        }
+        None => (WASI_ENOBUFS, address.len() as u32),
+    }
+}
@@ -0,0 +1,133 @@
+//! Networking backends provide the sockets behind `TcpListener` and `TcpStream`.
+//!
+//! By default the `NativeNetwork` backend is used, which maps directly to the host's sockets.
+//! Embedders can select another backend (e.g. `VirtualNetwork`) through `ProcessConfig`.
+
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
+
+use smol::future::Boxed;
+use smol::io::{AsyncRead, AsyncWrite};
+
+/// A backend capable of resolving addresses and creating listeners and connections.
+pub trait NetworkBackend: Send + Sync {
+    fn resolve(&self, address: &str) -> Boxed<io::Result<Vec<SocketAddr>>>;
+
+    /// Binds to the first address that is available.
+    fn bind(&self, addresses: Vec<SocketAddr>) -> Boxed<io::Result<Box<dyn Listener>>>;
+
+    /// Connects to the first address that accepts the connection.
+    fn connect(&self, addresses: Vec<SocketAddr>) -> Boxed<io::Result<Box<dyn Connection>>>;
+}
+
+pub trait Listener: Send + Sync {
+    fn accept(&self) -> Boxed<io::Result<(Box<dyn Connection>, SocketAddr)>>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A connection can be cloned, all clones share the same underlying socket.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Sync + Unpin {
+    fn box_clone(&self) -> Box<dyn Connection>;
+    /// Waits until data can be read without blocking. Returns a lower bound of the number of
+    /// readable bytes, 0 means that the peer closed the connection.
+    fn readable(&self) -> Boxed<io::Result<usize>>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()>;
+    fn set_ttl(&self, ttl: u32) -> io::Result<()>;
+    fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()>;
+}
+
+impl Clone for Box<dyn Connection> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Uses the host's network stack.
+#[derive(Clone, Copy, Default)]
+pub struct NativeNetwork;
+
+impl NetworkBackend for NativeNetwork {
+    fn resolve(&self, address: &str) -> Boxed<io::Result<Vec<SocketAddr>>> {
+        let address = address.to_owned();
+        Box::pin(async move { smol::net::resolve(address).await })
+    }
+
+    fn bind(&self, addresses: Vec<SocketAddr>) -> Boxed<io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let listener = smol::net::TcpListener::bind(addresses.as_slice()).await?;
+            Ok(Box::new(listener) as Box<dyn Listener>)
+        })
+    }
+
+    fn connect(&self, addresses: Vec<SocketAddr>) -> Boxed<io::Result<Box<dyn Connection>>> {
+        Box::pin(async move {
+            let stream = smol::net::TcpStream::connect(addresses.as_slice()).await?;
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        })
+    }
+}
+
+impl Listener for smol::net::TcpListener {
+    fn accept(&self) -> Boxed<io::Result<(Box<dyn Connection>, SocketAddr)>> {
+        let listener = self.clone();
+        Box::pin(async move {
+            let (stream, address) = listener.accept().await?;
+            Ok((Box::new(stream) as Box<dyn Connection>, address))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        smol::net::TcpListener::local_addr(self)
+    }
+}
+
+impl Connection for smol::net::TcpStream {
+    fn box_clone(&self) -> Box<dyn Connection> {
+        Box::new(self.clone())
+    }
+
+    fn readable(&self) -> Boxed<io::Result<usize>> {
+        let stream = self.clone();
+        Box::pin(async move { stream.peek(&mut [0; 4096]).await })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        smol::net::TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        smol::net::TcpStream::peer_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        smol::net::TcpStream::shutdown(self, how)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        smol::net::TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        smol::net::TcpStream::set_ttl(self, ttl)
+    }
+
+    fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        // The socket is only borrowed here, it must not be closed when `socket` is dropped.
+        #[cfg(unix)]
+        let socket = unsafe {
+            use std::os::unix::io::{AsRawFd, FromRawFd};
+            ManuallyDrop::new(socket2::Socket::from_raw_fd(self.as_raw_fd()))
+        };
+        #[cfg(windows)]
+        let socket = unsafe {
+            use std::os::windows::io::{AsRawSocket, FromRawSocket};
+            ManuallyDrop::new(socket2::Socket::from_raw_socket(self.as_raw_socket()))
+        };
+        socket.set_keepalive(keepalive)
+    }
+}
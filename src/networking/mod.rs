@@ -1,32 +1,58 @@
 pub mod api;
-
-use std::io;
+pub mod backend;
+pub mod policy;
+pub mod virtual_network;
+
+use std::future::Future;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
 use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
 
 use dashmap::DashMap;
 use lazy_static::lazy_static;
+use smol::{future, prelude::*, Timer};
 use uptown_funk::{FromWasmU32, ToWasmU32};
 
+use backend::{Connection, Listener, NetworkBackend};
+use policy::NetworkPolicy;
+
 lazy_static! {
     static ref SERIALIZED_TCP_STREAM: DashMap<usize, TcpStream> = DashMap::new();
 }
 
 static mut UNIQUE_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// Handles are cheap to clone and share the underlying socket, which is closed when the last
+/// handle is dropped. Host functions receive a clone of the handle held in `TcpState`, so
+/// removing it from the state with `tcp_listener_close` releases the socket.
 #[derive(Clone)]
-pub struct TcpListener(smol::net::TcpListener);
+pub struct TcpListener(Arc<dyn Listener>);
 
 impl TcpListener {
-    pub async fn bind(address: &str) -> Result<Self, io::Error> {
-        match smol::net::TcpListener::bind(address).await {
-            Ok(tcp_listener) => Ok(Self(tcp_listener)),
+    /// Binds to the first of the resolved `address`es that is allowed by the `policy`.
+    pub async fn bind(
+        address: &str,
+        policy: &NetworkPolicy,
+        backend: &dyn NetworkBackend,
+    ) -> Result<Self, io::Error> {
+        let addresses = policy.filter(backend.resolve(address).await?)?;
+        match backend.bind(addresses).await {
+            Ok(tcp_listener) => Ok(Self(tcp_listener.into())),
             Err(err) => Err(err),
         }
     }
 
     pub async fn accept(&self) -> Result<TcpStream, io::Error> {
         let (stream, address) = self.0.accept().await?;
-        Ok(TcpStream { stream, address })
+        Ok(TcpStream::new(stream, address))
+    }
+
+    /// Returns the local address this listener is bound to. Useful when binding to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.0.local_addr()
     }
 }
 
@@ -48,6 +74,8 @@ impl<'a> FromWasmU32<'a> for TcpListener {
     }
 }
 
+/// Result of a host call creating a listener. An error is written to the guest as the
+/// handle id 0, which is never used by `HashMapStore`.
 enum TcpListenerResult {
     Ok(TcpListener),
     Err(io::Error),
@@ -68,10 +96,101 @@ impl ToWasmU32 for TcpListenerResult {
     }
 }
 
+/// Like `TcpListener`, the socket is closed when the last handle is dropped. `tcp_stream_close`
+/// removes the process' handle.
 #[derive(Clone)]
 pub struct TcpStream {
-    stream: smol::net::TcpStream,
-    address: smol::net::SocketAddr,
+    stream: Box<dyn Connection>,
+    address: SocketAddr,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl TcpStream {
+    fn new(stream: Box<dyn Connection>, address: SocketAddr) -> Self {
+        Self {
+            stream,
+            address,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// Connects to the first of the resolved `address`es that is allowed by the `policy`.
+    pub async fn connect(
+        address: &str,
+        policy: &NetworkPolicy,
+        backend: &dyn NetworkBackend,
+    ) -> Result<Self, io::Error> {
+        let addresses = policy.filter_connect(backend.resolve(address).await?)?;
+        let stream = backend.connect(addresses).await?;
+        let address = stream.peer_addr()?;
+        Ok(Self::new(stream, address))
+    }
+
+    /// Reads into `bufs`, failing with `io::ErrorKind::TimedOut` if the stream's read timeout elapses.
+    pub async fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, io::Error> {
+        let timeout = self.read_timeout;
+        self.read_vectored_timeout(bufs, timeout).await
+    }
+
+    /// Reads into `bufs` with a per call `timeout`, ignoring the stream's read timeout.
+    pub async fn read_vectored_timeout(
+        &mut self,
+        bufs: &mut [IoSliceMut<'_>],
+        timeout: Option<Duration>,
+    ) -> Result<usize, io::Error> {
+        // Unlike `AsyncReadExt::read_vectored`, this doesn't tie the lifetime of the buffers to
+        // the borrow of the stream.
+        let stream = &mut self.stream;
+        let read = future::poll_fn(|cx| Pin::new(&mut *stream).poll_read_vectored(cx, bufs));
+        with_timeout(timeout, read).await
+    }
+
+    /// Writes `bufs`, failing with `io::ErrorKind::TimedOut` if the stream's write timeout elapses.
+    pub async fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, io::Error> {
+        with_timeout(self.write_timeout, self.stream.write_vectored(bufs)).await
+    }
+
+    /// Waits until the stream is readable. Returns a lower bound of the number of readable bytes,
+    /// 0 if the peer closed the connection.
+    pub async fn readable(&self) -> Result<usize, io::Error> {
+        self.stream.readable().await
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    /// Returns the address of the remote peer. It's captured when the connection is accepted.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.stream.local_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), io::Error> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), io::Error> {
+        self.stream.set_ttl(ttl)
+    }
+
+    /// Enables `SO_KEEPALIVE` with the given idle time, or disables it if `None` is passed.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> Result<(), io::Error> {
+        self.stream.set_keepalive(keepalive)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), io::Error> {
+        self.stream.shutdown(how)
+    }
 }
 
 impl<'a> FromWasmU32<'a> for TcpStream {
@@ -91,6 +210,9 @@ impl<'a> FromWasmU32<'a> for TcpStream {
         }
     }
 }
+
+/// Result of a host call creating a stream. An error is written to the guest as the
+/// handle id 0, which is never used by `HashMapStore`.
 enum TcpStreamResult {
     Ok(TcpStream),
     Err(io::Error),
@@ -110,3 +232,21 @@ impl ToWasmU32 for TcpStreamResult {
         }
     }
 }
+
+// Races the IO `future` against a timer. If the timer fires first the future is dropped.
+pub(crate) async fn with_timeout<T, F>(timeout: Option<Duration>, future: F) -> Result<T, io::Error>
+where
+    F: Future<Output = Result<T, io::Error>>,
+{
+    match timeout {
+        Some(timeout) => {
+            future
+                .or(async {
+                    Timer::after(timeout).await;
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "deadline elapsed"))
+                })
+                .await
+        }
+        None => future.await,
+    }
+}
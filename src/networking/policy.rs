@@ -0,0 +1,443 @@
+//! Network access policies restrict which addresses a process is allowed to bind to or connect to.
+//!
+//! A policy consists of an allow and a deny list of rules. An address is accessible if it doesn't
+//! match any deny rule and either the allow list is empty or it matches at least one allow rule.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+/// A range of IP addresses in CIDR notation combined with a range of ports.
+///
+/// Rules are parsed from strings of the form `network[:ports]`:
+/// * `10.0.0.0/8` - all ports of all addresses in the 10.0.0.0/8 network
+/// * `127.0.0.1:8080` - only port 8080 of 127.0.0.1
+/// * `[::1]/128:8000-9000` - IPv6 networks need to be wrapped in brackets
+/// * `*:80` - port 80 of any address
+#[derive(Clone, Debug)]
+pub struct NetworkRule {
+    // `None` matches any address.
+    network: Option<(IpAddr, u8)>,
+    ports: RangeInclusive<u16>,
+}
+
+impl NetworkRule {
+    /// IPv4-mapped IPv6 networks (`::ffff:0:0/96` and smaller) are stored as IPv4 networks.
+    pub fn new(network: Option<(IpAddr, u8)>, ports: RangeInclusive<u16>) -> Self {
+        let network = network.map(|(address, prefix)| match address {
+            IpAddr::V6(v6) if prefix >= 96 => match ipv4_mapped(&v6) {
+                Some(v4) => (IpAddr::V4(v4), prefix - 96),
+                None => (address, prefix),
+            },
+            _ => (address, prefix),
+        });
+        Self { network, ports }
+    }
+
+    pub fn matches(&self, address: &SocketAddr) -> bool {
+        self.ports.contains(&address.port()) && self.contains_ip(&address.ip())
+    }
+
+    fn contains_ip(&self, ip: &IpAddr) -> bool {
+        // Otherwise `[::ffff:10.0.0.1]` would get around a deny rule for `10.0.0.0/8`.
+        match (self.network, &canonical(*ip)) {
+            (None, _) => true,
+            (Some((IpAddr::V4(network), prefix)), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network) as u128,
+                u32::from(*ip) as u128,
+                prefix,
+                32,
+            ),
+            (Some((IpAddr::V6(network), prefix)), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(network), u128::from(*ip), prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+// IPv4-compatible addresses (`::a.b.c.d`) are deprecated, but still reach the IPv4 address on
+// some systems. `::` and `::1` are left alone.
+fn ipv4_compatible(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0..=1] => None,
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+// Rules are matched against the IPv4 address embedded in an IPv6 address, if there is one.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => ipv4_mapped(&v6)
+            .or_else(|| ipv4_compatible(&v6))
+            .map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// Connecting to an unspecified address reaches the local host.
+fn connect_target(address: SocketAddr) -> SocketAddr {
+    let ip = match canonical(address.ip()) {
+        IpAddr::V4(v4) if v4.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(v6) if v6.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        _ => address.ip(),
+    };
+    SocketAddr::new(ip, address.port())
+}
+
+// Compares the first `prefix` bits of two addresses that are `bits` long.
+fn prefix_matches(network: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    (network >> shift) == (ip >> shift)
+}
+
+impl FromStr for NetworkRule {
+    type Err = Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        // Split the rule into `address[/prefix]` and `ports`.
+        let (network, ports) = if let Some(rest) = rule.strip_prefix('[') {
+            let end = rest
+                .find(']')
+                .ok_or_else(|| anyhow!("Missing `]` in network rule `{}`", rule))?;
+            let (address, rest) = (&rest[..end], &rest[end + 1..]);
+            let (prefix, ports) = match rest.find(':') {
+                Some(colon) => (&rest[..colon], Some(&rest[colon + 1..])),
+                None => (rest, None),
+            };
+            let prefix = match prefix {
+                "" => None,
+                prefix => Some(prefix.strip_prefix('/').ok_or_else(|| {
+                    anyhow!(
+                        "Unexpected `{}` after `]` in network rule `{}`",
+                        prefix,
+                        rule
+                    )
+                })?),
+            };
+            ((address, prefix), ports)
+        } else {
+            let (network, ports) = match rule.rfind(':') {
+                Some(colon) => (&rule[..colon], Some(&rule[colon + 1..])),
+                None => (rule, None),
+            };
+            let mut parts = network.splitn(2, '/');
+            let address = parts.next().unwrap_or_default();
+            ((address, parts.next()), ports)
+        };
+
+        let bracketed = rule.starts_with('[');
+        let network = match network {
+            ("*", None) if !bracketed => None,
+            (address, prefix) => {
+                let address = IpAddr::from_str(address)
+                    .ok()
+                    .filter(|address| address.is_ipv6() == bracketed)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Invalid address `{}` in network rule `{}` (IPv6 addresses need to be wrapped in `[]`, IPv4 addresses must not be)",
+                            address,
+                            rule
+                        )
+                    })?;
+                let bits = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|prefix| *prefix <= bits)
+                        .ok_or_else(|| {
+                            anyhow!("Invalid prefix length in network rule `{}`", rule)
+                        })?,
+                    None => bits,
+                };
+                Some((address, prefix))
+            }
+        };
+
+        let ports = match ports {
+            None | Some("*") => 0..=u16::MAX,
+            Some(ports) => {
+                let invalid_port = || anyhow!("Invalid port range in network rule `{}`", rule);
+                let mut parts = ports.splitn(2, '-');
+                let start = parts.next().unwrap_or_default();
+                let start: u16 = start.parse().map_err(|_| invalid_port())?;
+                let end: u16 = match parts.next() {
+                    Some(end) => end.parse().map_err(|_| invalid_port())?,
+                    None => start,
+                };
+                if end < start {
+                    return Err(invalid_port());
+                }
+                start..=end
+            }
+        };
+
+        Ok(Self::new(network, ports))
+    }
+}
+
+/// Decides which addresses a process can access. The default policy allows everything.
+///
+/// The policy is attached to a process when it's spawned and inherited by all its children.
+#[derive(Clone, Debug)]
+pub struct NetworkPolicy {
+    enabled: bool,
+    allow: Vec<NetworkRule>,
+    deny: Vec<NetworkRule>,
+}
+
+impl Default for NetworkPolicy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+impl NetworkPolicy {
+    /// A policy without any restrictions.
+    pub fn allow_all() -> Self {
+        Self {
+            enabled: true,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+
+    /// A policy that doesn't give the process any network capabilities.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+
+    /// After the first allow rule is added, only addresses matching one of the allow rules are accessible.
+    pub fn allow(&mut self, rule: NetworkRule) {
+        self.allow.push(rule);
+    }
+
+    /// Deny rules take precedence over allow rules.
+    pub fn deny(&mut self, rule: NetworkRule) {
+        self.deny.push(rule);
+    }
+
+    /// Returns false if the process is not allowed to use the network at all.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_allowed(&self, address: &SocketAddr) -> bool {
+        self.enabled
+            && !self.deny.iter().any(|rule| rule.matches(address))
+            && (self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(address)))
+    }
+
+    /// Keeps only the addresses allowed by this policy.
+    /// Fails with `io::ErrorKind::PermissionDenied` if none of them is allowed.
+    pub fn filter(&self, addresses: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, io::Error> {
+        let allowed: Vec<SocketAddr> = addresses
+            .into_iter()
+            .filter(|address| self.is_allowed(address))
+            .collect();
+        if allowed.is_empty() {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Address not allowed by the network policy",
+            ))
+        } else {
+            Ok(allowed)
+        }
+    }
+
+    /// Like `filter`, but unspecified addresses (`0.0.0.0`, `[::]`) are replaced with loopback
+    /// before they are matched, because that's where a connection to them ends up.
+    pub fn filter_connect(&self, addresses: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, io::Error> {
+        self.filter(addresses.into_iter().map(connect_target).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: &str) -> NetworkRule {
+        rule.parse().unwrap()
+    }
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_edges() {
+        let any_v4 = rule("0.0.0.0/0");
+        assert!(any_v4.matches(&address("1.2.3.4:1")));
+        assert!(any_v4.matches(&address("255.255.255.255:65535")));
+        assert!(!any_v4.matches(&address("[::1]:1")));
+
+        let host = rule("10.0.0.1/32");
+        assert!(host.matches(&address("10.0.0.1:80")));
+        assert!(!host.matches(&address("10.0.0.2:80")));
+
+        let network = rule("10.0.0.0/8");
+        assert!(network.matches(&address("10.255.255.255:80")));
+        assert!(!network.matches(&address("11.0.0.0:80")));
+
+        let any_v6 = rule("[::]/0");
+        assert!(any_v6.matches(&address("[2001:db8::1]:443")));
+        assert!(!any_v6.matches(&address("1.2.3.4:443")));
+
+        let host_v6 = rule("[2001:db8::1]/128");
+        assert!(host_v6.matches(&address("[2001:db8::1]:443")));
+        assert!(!host_v6.matches(&address("[2001:db8::2]:443")));
+
+        // A missing prefix matches a single address.
+        assert!(rule("127.0.0.1").matches(&address("127.0.0.1:8080")));
+        assert!(!rule("127.0.0.1").matches(&address("127.0.0.2:8080")));
+        assert!(rule("*").matches(&address("[::1]:1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let network = rule("10.0.0.0/8");
+        assert!(network.matches(&address("[::ffff:10.1.2.3]:80")));
+        assert!(!network.matches(&address("[::ffff:11.1.2.3]:80")));
+
+        let mapped = rule("[::ffff:10.0.0.0]/104");
+        assert!(mapped.matches(&address("10.1.2.3:80")));
+        assert!(mapped.matches(&address("[::ffff:10.1.2.3]:80")));
+        assert!(!mapped.matches(&address("11.1.2.3:80")));
+
+        let mut policy = NetworkPolicy::allow_all();
+        policy.deny(rule("127.0.0.0/8"));
+        assert!(!policy.is_allowed(&address("[::ffff:127.0.0.1]:80")));
+        assert!(policy.is_allowed(&address("[::1]:80")));
+        assert!(!policy.is_allowed(&address("[::127.0.0.1]:80")));
+    }
+
+    #[test]
+    fn unspecified_addresses_connect_to_loopback() {
+        let mut policy = NetworkPolicy::allow_all();
+        policy.deny(rule("127.0.0.0/8"));
+        policy.deny(rule("[::1]"));
+        for unspecified in &["0.0.0.0:80", "[::]:80", "[::ffff:0.0.0.0]:80"] {
+            let error = policy
+                .filter_connect(vec![address(unspecified)])
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        }
+
+        let policy = NetworkPolicy::allow_all();
+        assert_eq!(
+            policy
+                .filter_connect(vec![address("0.0.0.0:80"), address("[::]:81")])
+                .unwrap(),
+            vec![address("127.0.0.1:80"), address("[::1]:81")]
+        );
+        // Binding to an unspecified address is fine.
+        assert_eq!(
+            policy.filter(vec![address("0.0.0.0:80")]).unwrap(),
+            vec![address("0.0.0.0:80")]
+        );
+    }
+
+    #[test]
+    fn ports() {
+        let single = rule("127.0.0.1:8080");
+        assert!(single.matches(&address("127.0.0.1:8080")));
+        assert!(!single.matches(&address("127.0.0.1:8081")));
+
+        let range = rule("[::1]/128:8000-9000");
+        assert!(range.matches(&address("[::1]:8000")));
+        assert!(range.matches(&address("[::1]:9000")));
+        assert!(!range.matches(&address("[::1]:7999")));
+        assert!(!range.matches(&address("[::1]:9001")));
+
+        let any_address = rule("*:80");
+        assert!(any_address.matches(&address("8.8.8.8:80")));
+        assert!(!any_address.matches(&address("8.8.8.8:81")));
+
+        assert!(rule("10.0.0.0/8:*").matches(&address("10.0.0.1:1")));
+        assert!(rule("10.0.0.0/8:0-65535").matches(&address("10.0.0.1:65535")));
+    }
+
+    #[test]
+    fn malformed_rules() {
+        for rule in &[
+            "",
+            "10.0.0.0/33",
+            "[::1]/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/",
+            "10.0.0",
+            "::1",
+            "[::1",
+            "[::1]x",
+            "[10.0.0.1]",
+            "10.0.0.1:",
+            "10.0.0.1:65536",
+            "10.0.0.1:9000-8000",
+            "10.0.0.1:80-",
+            "10.0.0.1:http",
+            "*/8",
+        ] {
+            assert!(
+                rule.parse::<NetworkRule>().is_err(),
+                "`{}` was accepted",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let mut policy = NetworkPolicy::allow_all();
+        assert!(policy.is_allowed(&address("1.2.3.4:80")));
+
+        policy.allow(rule("10.0.0.0/8"));
+        policy.deny(rule("10.0.0.1"));
+        assert!(policy.is_allowed(&address("10.0.0.2:80")));
+        assert!(!policy.is_allowed(&address("10.0.0.1:80")));
+        // Once there is an allow rule, everything else is denied.
+        assert!(!policy.is_allowed(&address("1.2.3.4:80")));
+
+        // The order in which rules are added doesn't matter.
+        let mut policy = NetworkPolicy::allow_all();
+        policy.deny(rule("10.0.0.1"));
+        policy.allow(rule("10.0.0.0/8"));
+        assert!(!policy.is_allowed(&address("10.0.0.1:80")));
+
+        let allowed = policy
+            .filter(vec![address("10.0.0.1:80"), address("10.0.0.2:80")])
+            .unwrap();
+        assert_eq!(allowed, vec![address("10.0.0.2:80")]);
+        let err = policy.filter(vec![address("10.0.0.1:80")]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut policy = NetworkPolicy::disabled();
+        assert!(!policy.is_enabled());
+        // `--net-allow` after `--no-net` doesn't enable the network again.
+        policy.allow(rule("*"));
+        assert!(!policy.is_allowed(&address("127.0.0.1:80")));
+        let err = policy.filter(vec![address("127.0.0.1:80")]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
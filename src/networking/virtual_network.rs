@@ -0,0 +1,800 @@
+//! An in-process network backend, used to deterministically test distributed code without
+//! touching real sockets.
+//!
+//! Each `VirtualNetwork` has its own address space. Any IP address can be bound and connections
+//! never leave the process. Hostnames can be registered with `add_host` and `localhost` always
+//! resolves to `127.0.0.1`. Faults (latency, dropped writes and connection resets) can be injected
+//! with `set_faults` and existing connections can be reset with `reset`. Latency is measured on the
+//! network's clock, see `set_clock`.
+//!
+//! Writes are buffered without limit, so they never block.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use smol::channel::{unbounded, Receiver, Sender};
+use smol::future::{self, Boxed};
+use smol::io::{AsyncRead, AsyncWrite};
+
+use super::backend::{Connection, Listener, NetworkBackend};
+use crate::wasi::clock::{Clock, SystemClock};
+
+// Ports assigned when binding to port 0 and to the client side of connections.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// Faults injected into all connections of a `VirtualNetwork`.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkFaults {
+    /// Delay before written data becomes readable by the peer. Also delays establishing connections.
+    pub latency: Duration,
+    /// Probability (0.0 - 1.0) that a write is silently discarded.
+    pub drop_probability: f64,
+    /// Probability (0.0 - 1.0) that a write resets the connection.
+    pub reset_probability: f64,
+}
+
+#[derive(Clone)]
+pub struct VirtualNetwork {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    listeners: Mutex<HashMap<SocketAddr, Sender<VirtualConnection>>>,
+    endpoints: Mutex<Vec<Weak<Endpoint>>>,
+    hosts: Mutex<HashMap<String, IpAddr>>,
+    faults: Mutex<NetworkFaults>,
+    rng: Mutex<SmallRng>,
+    clock: Mutex<Arc<dyn Clock>>,
+    next_port: Mutex<u16>,
+}
+
+impl Default for VirtualNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualNetwork {
+    pub fn new() -> Self {
+        Self::from_rng(SmallRng::from_entropy())
+    }
+
+    /// Uses `seed` for all random decisions (dropped writes and resets), so that test runs are reproducible.
+    pub fn with_seed(seed: u64) -> Self {
+        Self::from_rng(SmallRng::seed_from_u64(seed))
+    }
+
+    fn from_rng(rng: SmallRng) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                listeners: Mutex::new(HashMap::new()),
+                endpoints: Mutex::new(Vec::new()),
+                hosts: Mutex::new(HashMap::new()),
+                faults: Mutex::new(NetworkFaults::default()),
+                rng: Mutex::new(rng),
+                clock: Mutex::new(Arc::new(SystemClock::new())),
+                next_port: Mutex::new(FIRST_EPHEMERAL_PORT),
+            }),
+        }
+    }
+
+    /// Makes `hostname` resolve to `ip`.
+    pub fn add_host<S: Into<String>>(&self, hostname: S, ip: IpAddr) {
+        self.inner.hosts.lock().unwrap().insert(hostname.into(), ip);
+    }
+
+    /// Faults only affect connections established and data written after this call.
+    pub fn set_faults(&self, faults: NetworkFaults) {
+        *self.inner.faults.lock().unwrap() = faults;
+    }
+
+    /// Latency is measured on `clock` instead of the host's time. Pass the `VirtualClock` of the
+    /// processes using this network to control when delayed data arrives.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.inner.clock.lock().unwrap() = clock;
+    }
+
+    /// Resets all open connections from or to `address`.
+    pub fn reset(&self, address: SocketAddr) {
+        let endpoints = self.inner.endpoints.lock().unwrap();
+        for endpoint in endpoints.iter().filter_map(Weak::upgrade) {
+            if endpoint.local == address || endpoint.peer == address {
+                endpoint.reset();
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn resolve(&self, address: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(address) = address.parse::<SocketAddr>() {
+            return Ok(vec![address]);
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid socket address");
+        let colon = address.rfind(':').ok_or_else(invalid)?;
+        let port: u16 = address[colon + 1..].parse().map_err(|_| invalid())?;
+        let ip = match &address[..colon] {
+            "localhost" => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            host => *self
+                .hosts
+                .lock()
+                .unwrap()
+                .get(host)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown host"))?,
+        };
+        Ok(vec![SocketAddr::new(ip, port)])
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.lock().unwrap().clone()
+    }
+
+    fn ephemeral_port(&self) -> u16 {
+        let mut next_port = self.next_port.lock().unwrap();
+        let port = *next_port;
+        *next_port = next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        port
+    }
+
+    // Finds a free ephemeral port on `ip`, giving up after trying each ephemeral port once. A port
+    // is taken if a listener is bound to it or it's the local address of an open connection.
+    fn free_address(
+        &self,
+        listeners: &HashMap<SocketAddr, Sender<VirtualConnection>>,
+        ip: IpAddr,
+    ) -> Option<SocketAddr> {
+        let endpoints: Vec<Arc<Endpoint>> = self
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        (FIRST_EPHEMERAL_PORT..=u16::MAX)
+            .map(|_| SocketAddr::new(ip, self.ephemeral_port()))
+            .find(|address| {
+                !listeners.contains_key(address)
+                    && !endpoints.iter().any(|endpoint| endpoint.local == *address)
+            })
+    }
+
+    fn bind(self: &Arc<Self>, addresses: Vec<SocketAddr>) -> io::Result<Box<dyn Listener>> {
+        let mut listeners = self.listeners.lock().unwrap();
+        for address in addresses {
+            let address = if address.port() == 0 {
+                match self.free_address(&listeners, address.ip()) {
+                    Some(address) => address,
+                    None => continue,
+                }
+            } else if listeners.contains_key(&address) {
+                continue;
+            } else {
+                address
+            };
+            let (sender, receiver) = unbounded();
+            listeners.insert(address, sender);
+            return Ok(Box::new(VirtualListener {
+                address,
+                receiver,
+                inner: self.clone(),
+            }));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "Address already in use",
+        ))
+    }
+
+    fn connect(self: &Arc<Self>, addresses: Vec<SocketAddr>) -> io::Result<Box<dyn Connection>> {
+        let listeners = self.listeners.lock().unwrap();
+        for address in addresses {
+            let unspecified = match address.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let listener = listeners
+                .get(&address)
+                .or_else(|| listeners.get(&SocketAddr::new(unspecified, address.port())));
+            let listener = match listener {
+                Some(listener) => listener,
+                None => continue,
+            };
+
+            let client_ip = match address.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            let client_address = self.free_address(&listeners, client_ip).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "No free ephemeral port")
+            })?;
+            let client_to_server = Arc::new(Pipe::default());
+            let server_to_client = Arc::new(Pipe::default());
+            let client = Arc::new(Endpoint {
+                local: client_address,
+                peer: address,
+                incoming: server_to_client.clone(),
+                outgoing: client_to_server.clone(),
+                inner: self.clone(),
+            });
+            let server = Arc::new(Endpoint {
+                local: address,
+                peer: client_address,
+                incoming: client_to_server,
+                outgoing: server_to_client,
+                inner: self.clone(),
+            });
+
+            if listener
+                .try_send(VirtualConnection::new(server.clone()))
+                .is_err()
+            {
+                continue;
+            }
+            let mut endpoints = self.endpoints.lock().unwrap();
+            endpoints.retain(|endpoint| endpoint.strong_count() > 0);
+            endpoints.push(Arc::downgrade(&client));
+            endpoints.push(Arc::downgrade(&server));
+            return Ok(Box::new(VirtualConnection::new(client)));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "Connection refused",
+        ))
+    }
+
+    // Returns the current latency and if the next write should be dropped or reset the connection.
+    fn roll_faults(&self) -> (Duration, bool, bool) {
+        let faults = self.faults.lock().unwrap().clone();
+        let mut rng = self.rng.lock().unwrap();
+        let dropped = faults.drop_probability > 0.0 && rng.gen::<f64>() < faults.drop_probability;
+        let reset = faults.reset_probability > 0.0 && rng.gen::<f64>() < faults.reset_probability;
+        (faults.latency, dropped, reset)
+    }
+}
+
+impl NetworkBackend for VirtualNetwork {
+    fn resolve(&self, address: &str) -> Boxed<io::Result<Vec<SocketAddr>>> {
+        Box::pin(future::ready(self.inner.resolve(address)))
+    }
+
+    fn bind(&self, addresses: Vec<SocketAddr>) -> Boxed<io::Result<Box<dyn Listener>>> {
+        Box::pin(future::ready(self.inner.bind(addresses)))
+    }
+
+    fn connect(&self, addresses: Vec<SocketAddr>) -> Boxed<io::Result<Box<dyn Connection>>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let latency = inner.faults.lock().unwrap().latency;
+            if latency > Duration::from_secs(0) {
+                let clock = inner.clock();
+                let deadline = clock.monotonic().saturating_add(latency.as_nanos() as u64);
+                clock.sleep_until(deadline).await;
+            }
+            inner.connect(addresses)
+        })
+    }
+}
+
+struct VirtualListener {
+    address: SocketAddr,
+    receiver: Receiver<VirtualConnection>,
+    inner: Arc<Inner>,
+}
+
+impl Listener for VirtualListener {
+    fn accept(&self) -> Boxed<io::Result<(Box<dyn Connection>, SocketAddr)>> {
+        let receiver = self.receiver.clone();
+        Box::pin(async move {
+            match receiver.recv().await {
+                Ok(connection) => {
+                    let peer = connection.endpoint.peer;
+                    Ok((Box::new(connection) as Box<dyn Connection>, peer))
+                }
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Listener closed",
+                )),
+            }
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+}
+
+impl Drop for VirtualListener {
+    fn drop(&mut self) {
+        self.inner.listeners.lock().unwrap().remove(&self.address);
+    }
+}
+
+// One direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+}
+
+#[derive(Default)]
+struct PipeState {
+    // Data and the time on the network's monotonic clock at which it becomes readable.
+    chunks: VecDeque<(u64, Vec<u8>)>,
+    write_closed: bool,
+    read_closed: bool,
+    reset: bool,
+    // Clones of a connection can wait for data at the same time, all of them are woken up.
+    readers: Vec<Waker>,
+}
+
+impl Pipe {
+    fn close_write(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.write_closed = true;
+        state.wake_readers();
+    }
+
+    fn close_read(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.read_closed = true;
+        state.chunks.clear();
+        state.wake_readers();
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.reset = true;
+        state.chunks.clear();
+        state.wake_readers();
+    }
+}
+
+impl PipeState {
+    fn add_reader(&mut self, waker: &Waker) {
+        if !self.readers.iter().any(|reader| reader.will_wake(waker)) {
+            self.readers.push(waker.clone());
+        }
+    }
+
+    fn wake_readers(&mut self) {
+        for waker in self.readers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    // Copies all data that is readable at `now` into `bufs`, returns the number of bytes copied.
+    fn read_into(&mut self, bufs: &mut [IoSliceMut<'_>], now: u64) -> usize {
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.chunks.front_mut() {
+                    Some((ready_at, chunk)) if *ready_at <= now => {
+                        let n = cmp::min(buf.len() - filled, chunk.len());
+                        buf[filled..filled + n].copy_from_slice(&chunk[..n]);
+                        chunk.drain(..n);
+                        if chunk.is_empty() {
+                            self.chunks.pop_front();
+                        }
+                        filled += n;
+                    }
+                    _ => break,
+                }
+            }
+            read += filled;
+            if filled < buf.len() {
+                break;
+            }
+        }
+        read
+    }
+}
+
+// One side of a connection, shared between all clones of a `VirtualConnection`.
+struct Endpoint {
+    local: SocketAddr,
+    peer: SocketAddr,
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    inner: Arc<Inner>,
+}
+
+impl Endpoint {
+    fn reset(&self) {
+        self.incoming.reset();
+        self.outgoing.reset();
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.outgoing.close_write();
+        self.incoming.close_read();
+    }
+}
+
+struct VirtualConnection {
+    endpoint: Arc<Endpoint>,
+    // Wakes up the reader once delayed data becomes readable, together with its deadline. Only
+    // accessed through `&mut self`, the mutex just makes the connection `Sync`.
+    sleep: Option<(u64, Mutex<Boxed<()>>)>,
+}
+
+impl VirtualConnection {
+    fn new(endpoint: Arc<Endpoint>) -> Self {
+        Self {
+            endpoint,
+            sleep: None,
+        }
+    }
+}
+
+impl VirtualConnection {
+    // Returns the number of bytes that are readable now, waiting until there is at least one or
+    // the connection is closed.
+    fn poll_readable(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        loop {
+            let mut state = self.endpoint.incoming.state.lock().unwrap();
+            if state.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            let clock = self.endpoint.inner.clock();
+            let now = clock.monotonic();
+            match state.chunks.front() {
+                Some((ready_at, _)) if *ready_at > now => {
+                    let ready_at = *ready_at;
+                    state.add_reader(cx.waker());
+                    drop(state);
+                    if !matches!(self.sleep, Some((deadline, _)) if deadline == ready_at) {
+                        self.sleep = Some((ready_at, Mutex::new(clock.sleep_until(ready_at))));
+                    }
+                    let (_, sleep) = self.sleep.as_mut().unwrap();
+                    match sleep.get_mut().unwrap().as_mut().poll(cx) {
+                        Poll::Ready(_) => {
+                            self.sleep = None;
+                            continue;
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                Some(_) => {
+                    let readable = state
+                        .chunks
+                        .iter()
+                        .take_while(|(ready_at, _)| *ready_at <= now)
+                        .map(|(_, chunk)| chunk.len())
+                        .sum();
+                    return Poll::Ready(Ok(readable));
+                }
+                None if state.write_closed || state.read_closed => return Poll::Ready(Ok(0)),
+                None => {
+                    state.add_reader(cx.waker());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for VirtualConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_read_vectored(cx, &mut [IoSliceMut::new(buf)])
+    }
+
+    fn poll_read_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.poll_readable(cx) {
+            Poll::Ready(Ok(_)) => {
+                let now = self.endpoint.inner.clock().monotonic();
+                let mut state = self.endpoint.incoming.state.lock().unwrap();
+                Poll::Ready(Ok(state.read_into(bufs, now)))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for VirtualConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let endpoint = &self.endpoint;
+        {
+            let state = endpoint.outgoing.state.lock().unwrap();
+            if state.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            if state.write_closed || state.read_closed {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+        }
+
+        let (latency, dropped, reset) = endpoint.inner.roll_faults();
+        if reset {
+            endpoint.reset();
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+
+        let total = bufs.iter().map(|buf| buf.len()).sum();
+        if !dropped && total > 0 {
+            let mut data = Vec::with_capacity(total);
+            for buf in bufs {
+                data.extend_from_slice(buf);
+            }
+            let ready_at = endpoint
+                .inner
+                .clock()
+                .monotonic()
+                .saturating_add(latency.as_nanos() as u64);
+            let mut state = endpoint.outgoing.state.lock().unwrap();
+            state.chunks.push_back((ready_at, data));
+            state.wake_readers();
+        }
+        Poll::Ready(Ok(total))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.endpoint.outgoing.close_write();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Connection for VirtualConnection {
+    fn box_clone(&self) -> Box<dyn Connection> {
+        Box::new(VirtualConnection::new(self.endpoint.clone()))
+    }
+
+    fn readable(&self) -> Boxed<io::Result<usize>> {
+        let mut connection = VirtualConnection::new(self.endpoint.clone());
+        Box::pin(future::poll_fn(move |cx| connection.poll_readable(cx)))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.endpoint.local)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.endpoint.peer)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match how {
+            Shutdown::Read => self.endpoint.incoming.close_read(),
+            Shutdown::Write => self.endpoint.outgoing.close_write(),
+            Shutdown::Both => {
+                self.endpoint.incoming.close_read();
+                self.endpoint.outgoing.close_write();
+            }
+        }
+        Ok(())
+    }
+
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_ttl(&self, _ttl: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_keepalive(&self, _keepalive: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::wasi::clock::VirtualClock;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    // Returns both sides of a new connection, the client first.
+    fn connect(network: &VirtualNetwork) -> (Box<dyn Connection>, Box<dyn Connection>) {
+        future::block_on(async {
+            let listener = network.bind(vec![address("127.0.0.1:0")]).await.unwrap();
+            let client = network
+                .connect(vec![listener.local_addr().unwrap()])
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            (client, server)
+        })
+    }
+
+    // Writes the bytes 0..count one at a time, returns what arrived and if the connection was reset.
+    fn write_bytes(network: &VirtualNetwork, count: u8) -> (Vec<u8>, bool) {
+        let (mut client, mut server) = connect(network);
+        future::block_on(async {
+            let mut reset = false;
+            for byte in 0..count {
+                if client.write_all(&[byte]).await.is_err() {
+                    reset = true;
+                    break;
+                }
+            }
+            drop(client);
+            let mut received = Vec::new();
+            if server.read_to_end(&mut received).await.is_err() {
+                reset = true;
+            }
+            (received, reset)
+        })
+    }
+
+    #[test]
+    fn connect_and_accept() {
+        let network = VirtualNetwork::new();
+        future::block_on(async {
+            let listener = network.bind(vec![address("0.0.0.0:8080")]).await.unwrap();
+            let target = network.resolve("localhost:8080").await.unwrap();
+            let mut client = network.connect(target).await.unwrap();
+            let (mut server, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+            assert_eq!(client.peer_addr().unwrap(), address("127.0.0.1:8080"));
+
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+
+            client.shutdown(Shutdown::Write).unwrap();
+            assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn clients_get_free_ports() {
+        let network = VirtualNetwork::new();
+        future::block_on(async {
+            // The port the first client would get otherwise.
+            let taken = address(&format!("127.0.0.1:{}", FIRST_EPHEMERAL_PORT));
+            let _taken = network.bind(vec![taken]).await.unwrap();
+            let listener = network.bind(vec![address("127.0.0.1:8080")]).await.unwrap();
+            let client = network
+                .connect(vec![address("127.0.0.1:8080")])
+                .await
+                .unwrap();
+            let (_server, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            assert_ne!(peer, taken);
+
+            // Listeners don't get the port of an open connection either.
+            *network.inner.next_port.lock().unwrap() = peer.port();
+            let listener = network.bind(vec![address("127.0.0.1:0")]).await.unwrap();
+            assert_ne!(listener.local_addr().unwrap(), peer);
+        });
+    }
+
+    #[test]
+    fn unknown_addresses() {
+        let network = VirtualNetwork::new();
+        future::block_on(async {
+            let err = network.resolve("example.com:80").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            network.add_host("example.com", "10.0.0.1".parse().unwrap());
+            let target = network.resolve("example.com:80").await.unwrap();
+            assert_eq!(target, vec![address("10.0.0.1:80")]);
+
+            // Nobody is listening.
+            let err = network.connect(target).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+    }
+
+    #[test]
+    fn seeded_faults_are_reproducible() {
+        let faults = NetworkFaults {
+            drop_probability: 0.5,
+            reset_probability: 0.02,
+            ..NetworkFaults::default()
+        };
+        let run = |seed| {
+            let network = VirtualNetwork::with_seed(seed);
+            network.set_faults(faults.clone());
+            (0..8)
+                .map(|_| write_bytes(&network, 64))
+                .collect::<Vec<_>>()
+        };
+
+        let first = run(7);
+        assert_eq!(first, run(7));
+        assert!(first.iter().any(|(received, _)| received.len() < 64));
+        assert!(first.iter().any(|(_, reset)| *reset));
+    }
+
+    #[test]
+    fn latency_follows_the_clock() {
+        let clock = Arc::new(VirtualClock::new(Duration::from_secs(0)));
+        let network = VirtualNetwork::new();
+        network.set_clock(clock.clone());
+        let (mut client, server) = connect(&network);
+        network.set_faults(NetworkFaults {
+            latency: Duration::from_secs(1),
+            ..NetworkFaults::default()
+        });
+
+        future::block_on(client.write_all(b"late")).unwrap();
+        let mut readable = server.readable();
+        assert!(future::block_on(future::poll_once(&mut readable)).is_none());
+        clock.advance(Duration::from_millis(999));
+        assert!(future::block_on(future::poll_once(&mut readable)).is_none());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(future::block_on(readable).unwrap(), 4);
+
+        // Establishing connections is delayed too.
+        let listener = future::block_on(network.bind(vec![address("127.0.0.1:0")])).unwrap();
+        let mut connecting = network.connect(vec![listener.local_addr().unwrap()]);
+        assert!(future::block_on(future::poll_once(&mut connecting)).is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(future::block_on(connecting).is_ok());
+    }
+
+    #[test]
+    fn reset_connection() {
+        let network = VirtualNetwork::new();
+        let (mut client, mut server) = connect(&network);
+        network.reset(server.local_addr().unwrap());
+        future::block_on(async {
+            let err = server.read(&mut [0; 4]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            let err = client.write_all(b"ping").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        });
+    }
+
+    #[test]
+    fn all_waiting_readers_are_woken_up() {
+        let network = VirtualNetwork::new();
+        let (mut client, server) = connect(&network);
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let readable = server.readable();
+                thread::spawn(move || future::block_on(readable).unwrap())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        future::block_on(client.write_all(b"ping")).unwrap();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 4);
+        }
+    }
+}
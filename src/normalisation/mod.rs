@@ -8,23 +8,293 @@
 //! This module will grow with time, as more languages are supported by Lunatic and more edge cases
 //! are encountered.
 
-use anyhow::Error;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
 use walrus::Module;
 
 mod reduction_counting;
 mod shared_memory;
 mod stdlib;
 
-/// Patches:
-/// * Add reduction counters and yielding to functions and ~hot loops~.
-/// * Add low level functions required by the Lunatic stdlib.
-/// * Transforming defined memories into imported (shared) ones.
+pub use reduction_counting::{CostModel, ReductionCounting, DEFAULT_REDUCTION_LIMIT};
+pub use shared_memory::SharedMemory;
+pub use stdlib::Stdlib;
+
+/// Applies the default `Pipeline` to the module. Returns the initial and maximum size of the
+/// memory imported from Lunatic, `(0, None)` if there is none.
 pub fn patch(module_buffer: &[u8]) -> Result<((u32, Option<u32>), Vec<u8>), Error> {
-    let mut module = Module::from_buffer(&module_buffer)?;
+    Pipeline::default().apply(module_buffer)
+}
+
+/// A transformation applied to WASM modules before they are compiled, e.g. a shim for a specific
+/// language.
+pub trait Patch: Send + Sync {
+    /// Identifies the patch inside of a `Pipeline`.
+    fn name(&self) -> &str;
+
+    fn patch(&self, module: &mut Module) -> Result<(), Error>;
+}
+
+/// The patches applied to a module, in order.
+///
+/// The default pipeline contains the patches Lunatic itself needs:
+/// * `reduction_counting` - Add reduction counters and yielding to functions and loops.
+/// * `stdlib` - Add low level functions required by the Lunatic stdlib.
+/// * `shared_memory` - Transforming defined memories into imported (shared) ones.
+///
+/// Host functions only access the memory imported from `lunatic::memory`. `shared_memory` can only
+/// be left out for modules without a memory, `apply` fails for all others.
+#[derive(Clone)]
+pub struct Pipeline {
+    patches: Vec<Arc<dyn Patch>>,
+}
+
+impl Pipeline {
+    /// A pipeline without any patches.
+    pub fn empty() -> Self {
+        Self {
+            patches: Vec::new(),
+        }
+    }
+
+    /// The default pipeline, counting reductions with the `cost_model`.
+    pub fn with_cost_model(cost_model: CostModel) -> Self {
+        Self::empty()
+            .push(ReductionCounting { cost_model })
+            .push(Stdlib)
+            .push(SharedMemory)
+    }
+
+    /// A pipeline of Lunatic's own patches in the given order, e.g. to leave some of them out.
+    pub fn from_names(names: &[&str], cost_model: CostModel) -> Result<Self, Error> {
+        let mut pipeline = Self::empty();
+        for name in names {
+            pipeline = match *name {
+                "reduction_counting" => pipeline.push(ReductionCounting { cost_model }),
+                "stdlib" => pipeline.push(Stdlib),
+                "shared_memory" => pipeline.push(SharedMemory),
+                _ => return Err(anyhow!("Unknown patch `{}`", name)),
+            };
+        }
+        Ok(pipeline)
+    }
+
+    /// Adds the patch to the end of the pipeline.
+    pub fn push(mut self, patch: impl Patch + 'static) -> Self {
+        self.patches.push(Arc::new(patch));
+        self
+    }
+
+    /// Adds the patch in front of the patch named `before`.
+    pub fn insert_before(
+        mut self,
+        before: &str,
+        patch: impl Patch + 'static,
+    ) -> Result<Self, Error> {
+        let index = self.position(before)?;
+        self.patches.insert(index, Arc::new(patch));
+        Ok(self)
+    }
+
+    /// Removes the patch named `name`.
+    pub fn without(mut self, name: &str) -> Result<Self, Error> {
+        let index = self.position(name)?;
+        self.patches.remove(index);
+        Ok(self)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.patches.iter().map(|patch| patch.name()).collect()
+    }
+
+    /// Applies all patches to the module. Returns the initial and maximum size of the memory
+    /// imported from Lunatic, `(0, None)` if there is none.
+    pub fn apply(&self, module_buffer: &[u8]) -> Result<((u32, Option<u32>), Vec<u8>), Error> {
+        let mut module = Module::from_buffer(module_buffer)?;
+
+        for patch in &self.patches {
+            patch
+                .patch(&mut module)
+                .map_err(|err| anyhow!("Patch `{}` failed: {}", patch.name(), err))?;
+        }
+        let mut memory = (0, None);
+        for module_memory in module.memories.iter() {
+            match module_memory.import.map(|import| module.imports.get(import)) {
+                Some(import) if import.module == "lunatic" && import.name == "memory" => {
+                    memory = (module_memory.initial, module_memory.maximum);
+                }
+                // Host functions would read and write the process' memory instead of this one.
+                _ => {
+                    return Err(anyhow!(
+                        "The module's memory is not imported from `lunatic::memory`, the `shared_memory` patch is required"
+                    ))
+                }
+            }
+        }
+
+        Ok((memory, module.emit_wasm()))
+    }
+
+    fn position(&self, name: &str) -> Result<usize, Error> {
+        self.patches
+            .iter()
+            .position(|patch| patch.name() == name)
+            .ok_or_else(|| anyhow!("Unknown patch `{}`", name))
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::with_cost_model(CostModel::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use walrus::{ExportItem, FunctionBuilder};
+
+    use super::*;
+
+    // Exports an empty function under its name.
+    struct ExportFunction(&'static str);
+
+    impl Patch for ExportFunction {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn patch(&self, module: &mut Module) -> Result<(), Error> {
+            let function = FunctionBuilder::new(&mut module.types, &[], &[]);
+            let function = function.finish(Vec::new(), &mut module.funcs);
+            module.exports.add(self.0, ExportItem::Function(function));
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    impl Patch for Failing {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn patch(&self, _module: &mut Module) -> Result<(), Error> {
+            Err(anyhow!("not today"))
+        }
+    }
+
+    #[test]
+    fn building_pipelines() {
+        let pipeline = Pipeline::default();
+        assert_eq!(
+            pipeline.names(),
+            ["reduction_counting", "stdlib", "shared_memory"]
+        );
+
+        let pipeline = pipeline.push(ExportFunction("last"));
+        assert_eq!(pipeline.names().last(), Some(&"last"));
+
+        let pipeline = pipeline
+            .insert_before("stdlib", ExportFunction("shim"))
+            .unwrap();
+        assert_eq!(
+            pipeline.names(),
+            [
+                "reduction_counting",
+                "shim",
+                "stdlib",
+                "shared_memory",
+                "last"
+            ]
+        );
+        let error = pipeline
+            .clone()
+            .insert_before("missing", ExportFunction("shim"))
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Unknown patch `missing`");
+
+        let pipeline = pipeline.without("reduction_counting").unwrap();
+        assert_eq!(
+            pipeline.names(),
+            ["shim", "stdlib", "shared_memory", "last"]
+        );
+        let error = pipeline.without("reduction_counting").err().unwrap();
+        assert_eq!(error.to_string(), "Unknown patch `reduction_counting`");
+    }
+
+    #[test]
+    fn pipelines_from_names() {
+        let pipeline = Pipeline::from_names(
+            &["shared_memory", "reduction_counting"],
+            CostModel::Weighted,
+        )
+        .unwrap();
+        assert_eq!(pipeline.names(), ["shared_memory", "reduction_counting"]);
+        assert!(Pipeline::from_names(&[], CostModel::Uniform)
+            .unwrap()
+            .names()
+            .is_empty());
+
+        let error = Pipeline::from_names(&["stdlib", "shim"], CostModel::Uniform)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Unknown patch `shim`");
+    }
+
+    #[test]
+    fn custom_patches_are_applied() {
+        let wasm = wat::parse_str("(module (memory 2 3))").unwrap();
+        let pipeline = Pipeline::empty()
+            .push(ExportFunction("first"))
+            .push(SharedMemory)
+            .push(ExportFunction("second"));
+        let (memory, patched) = pipeline.apply(&wasm).unwrap();
+        assert_eq!(memory, (2, Some(3)));
+
+        let module = Module::from_buffer(&patched).unwrap();
+        let exports: Vec<&str> = module
+            .exports
+            .iter()
+            .map(|export| export.name.as_str())
+            .collect();
+        assert!(exports.contains(&"first"));
+        assert!(exports.contains(&"second"));
+
+        let error = Pipeline::empty().push(Failing).apply(&wasm).err().unwrap();
+        assert_eq!(error.to_string(), "Patch `failing` failed: not today");
+    }
+
+    #[test]
+    fn failing_stdlib_patch() {
+        // The main function table is ambiguous.
+        let wasm = wat::parse_str("(module (table 1 funcref) (table 1 funcref))").unwrap();
+        let error = Pipeline::default().apply(&wasm).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Patch `stdlib` failed: module contains more than one function table"
+        );
+    }
 
-    reduction_counting::patch(&mut module);
-    stdlib::patch(&mut module);
-    let memory = shared_memory::patch(&mut module);
+    #[test]
+    fn shared_memory_is_required() {
+        let wasm = wat::parse_str("(module (memory 1))").unwrap();
+        let pipelines = vec![
+            Pipeline::default().without("shared_memory").unwrap(),
+            Pipeline::from_names(&["reduction_counting", "stdlib"], CostModel::Uniform).unwrap(),
+            Pipeline::empty(),
+        ];
+        for pipeline in pipelines {
+            let error = pipeline.apply(&wasm).err().unwrap();
+            assert_eq!(
+                error.to_string(),
+                "The module's memory is not imported from `lunatic::memory`, the `shared_memory` patch is required"
+            );
+        }
 
-    Ok((memory, module.emit_wasm()))
+        // Modules without a memory don't need it.
+        let wasm = wat::parse_str("(module)").unwrap();
+        assert_eq!(Pipeline::empty().apply(&wasm).unwrap().0, (0, None));
+    }
 }
@@ -1,52 +1,185 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
 use walrus::*;
 
-// How many operations should happen before we yield
-const REDUCTION_LIMIT: i32 = 10_000;
+use super::Patch;
+
+/// How many reductions can happen before a process yields, if the process configuration doesn't
+/// set a limit.
+pub const DEFAULT_REDUCTION_LIMIT: u32 = 10_000;
+
+/// What a reduction is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CostModel {
+    /// Each function call and loop iteration is one reduction.
+    Uniform,
+    /// Each function body, block, `if` branch and loop iteration counts as many reductions as it
+    /// directly contains instructions, so that scheduling reflects the actual work done. They are
+    /// charged when they are entered, instructions of nested blocks when those are entered.
+    Weighted,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel::Uniform
+    }
+}
+
+/// Parsed from `uniform` or `weighted`.
+impl FromStr for CostModel {
+    type Err = Error;
 
-/// Modifies the WASM binary to add a `yield` import call after `REDUCTION_LIMIT` of **operations**
-/// has been reached. Currently only function calls are counted as **operations**.
-/// TODO: If there is a tight loop without function calls, this becomes an issue.
+    fn from_str(cost_model: &str) -> Result<Self, Self::Err> {
+        match cost_model {
+            "uniform" => Ok(CostModel::Uniform),
+            "weighted" => Ok(CostModel::Weighted),
+            _ => Err(anyhow!("Unknown cost model `{}`", cost_model)),
+        }
+    }
+}
+
+/// Modifies the WASM binary to add a `yield` import call after the reduction limit has been
+/// reached. Function calls and loop iterations are counted as reductions, so that even a tight
+/// loop without calls eventually yields. The `cost_model` decides how much each of them adds.
 /// The idea behind this is to not allow any WASM Instance to block a thread in the async environment
 /// for too long.
 ///
 /// To achive this the following things are inserted into the WASM module:
 /// * A global variable to hold the current count
+/// * An import of the reduction limit global, so that the limit can be set per process
 /// * An import to the host provided `yield` function
-/// * Instructions on top of each function to check if we reached the `REDUCTION_LIMIT` and yield
-pub fn patch(module: &mut Module) {
+/// * Instructions on top of each function and each loop body to check if we reached the limit
+///   and yield. With the weighted cost model also on top of each block and `if` branch.
+pub fn patch(module: &mut Module, cost_model: CostModel) {
+    let (limit, _) = module.add_import_global("lunatic", "reduction_limit", ValType::I32, false);
     let counter = module
         .globals
         .add_local(ValType::I32, true, InitExpr::Value(ir::Value::I32(0)));
     let yield_type = module.types.add(&[], &[]);
     let yield_import = module.add_import_func("lunatic", "yield_", yield_type);
 
+    let instrumentation = Instrumentation {
+        counter,
+        limit,
+        yield_func: yield_import.0,
+        cost_model,
+    };
     for function in module.funcs.iter_mut() {
         match &mut function.kind {
-            FunctionKind::Local(function) => patch_function(function, counter, yield_import.0),
+            FunctionKind::Local(function) => patch_function(function, &instrumentation),
             _ => continue,
         }
     }
 }
 
-fn patch_function(function: &mut LocalFunction, counter: GlobalId, yield_func: FunctionId) {
+/// Preemption of processes through reduction counting.
+pub struct ReductionCounting {
+    pub cost_model: CostModel,
+}
+
+impl Patch for ReductionCounting {
+    fn name(&self) -> &str {
+        "reduction_counting"
+    }
+
+    fn patch(&self, module: &mut Module) -> Result<(), Error> {
+        patch(module, self.cost_model);
+        Ok(())
+    }
+}
+
+struct Instrumentation {
+    counter: GlobalId,
+    limit: GlobalId,
+    yield_func: FunctionId,
+    cost_model: CostModel,
+}
+
+fn patch_function(function: &mut LocalFunction, instrumentation: &Instrumentation) {
+    // Branching to a loop jumps to the start of its body, so the check runs on every iteration.
+    let mut sequences = vec![function.entry_block()];
+    let all_blocks = instrumentation.cost_model == CostModel::Weighted;
+    find_checked_sequences(function, function.entry_block(), all_blocks, &mut sequences);
+
+    // Costs need to be calculated before any checks are inserted.
+    let costs: Vec<i32> = sequences
+        .iter()
+        .map(|&sequence| match instrumentation.cost_model {
+            CostModel::Uniform => 1,
+            CostModel::Weighted => function.block(sequence).len() as i32,
+        })
+        .collect();
+
     let builder = function.builder_mut();
-    let mut body = builder.func_body();
+    for (sequence, cost) in sequences.into_iter().zip(costs) {
+        // Empty blocks, like a missing `else` branch, don't cost anything.
+        if cost > 0 {
+            insert_reduction_check(&mut builder.instr_seq(sequence), cost, instrumentation);
+        }
+    }
+}
+
+// Collects the bodies of all loops nested inside of the instruction sequence, and if `all_blocks`
+// is set also the bodies of blocks and `if` branches.
+fn find_checked_sequences(
+    function: &LocalFunction,
+    sequence: ir::InstrSeqId,
+    all_blocks: bool,
+    sequences: &mut Vec<ir::InstrSeqId>,
+) {
+    for (instr, _) in function.block(sequence).iter() {
+        match instr {
+            ir::Instr::Loop(ir::Loop { seq }) => {
+                sequences.push(*seq);
+                find_checked_sequences(function, *seq, all_blocks, sequences);
+            }
+            ir::Instr::Block(ir::Block { seq }) => {
+                if all_blocks {
+                    sequences.push(*seq);
+                }
+                find_checked_sequences(function, *seq, all_blocks, sequences);
+            }
+            ir::Instr::IfElse(ir::IfElse {
+                consequent,
+                alternative,
+            }) => {
+                for &seq in &[*consequent, *alternative] {
+                    if all_blocks {
+                        sequences.push(seq);
+                    }
+                    find_checked_sequences(function, seq, all_blocks, sequences);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn insert_reduction_check(
+    body: &mut InstrSeqBuilder,
+    cost: i32,
+    instrumentation: &Instrumentation,
+) {
+    let counter = instrumentation.counter;
     body.block_at(0, None, |block| {
         // Algorithm:
-        // 1. Increment the reduction counter global
-        // 2. Check if the global reached 100_000, if yes yiled and reset reduction counter
+        // 1. Add the cost to the reduction counter global
+        // 2. Check if the global reached the limit, if yes yield and reset reduction counter
         block
             .global_get(counter)
-            .i32_const(1)
+            .i32_const(cost)
             .binop(ir::BinaryOp::I32Add)
             .global_set(counter)
             .global_get(counter)
-            .i32_const(REDUCTION_LIMIT)
-            .binop(ir::BinaryOp::I32GtS)
+            .global_get(instrumentation.limit)
+            .binop(ir::BinaryOp::I32GtU)
             .if_else(
                 None,
                 |then| {
-                    then.call(yield_func).i32_const(0).global_set(counter);
+                    then.call(instrumentation.yield_func)
+                        .i32_const(0)
+                        .global_set(counter);
                 },
                 |_else| {},
             );
@@ -1,8 +1,10 @@
+use anyhow::Error;
 use walrus::*;
 
+use super::Patch;
+
 /// Finds memory with the index 0 and turns it into an import.
-/// Returns the initial and maximum memory sizes.
-pub fn patch(module: &mut Module) -> (u32, Option<u32>) {
+pub fn patch(module: &mut Module) {
     if let Some(memory) = module.memories.iter_mut().next() {
         let memory_id = memory.id();
         let memory_import = module
@@ -10,8 +12,19 @@ pub fn patch(module: &mut Module) -> (u32, Option<u32>) {
             .add("lunatic", "memory", ImportKind::Memory(memory_id));
         memory.shared = false;
         memory.import = Some(memory_import);
-        (memory.initial, memory.maximum)
-    } else {
-        (0, None)
+    }
+}
+
+/// The memory is provided by Lunatic, so that it can be created per process.
+pub struct SharedMemory;
+
+impl Patch for SharedMemory {
+    fn name(&self) -> &str {
+        "shared_memory"
+    }
+
+    fn patch(&self, module: &mut Module) -> Result<(), Error> {
+        patch(module);
+        Ok(())
     }
 }
@@ -1,10 +1,14 @@
+use anyhow::Error;
 use walrus::*;
 
+use super::Patch;
+
 /// Adds WASM functions required by the stdlib implementation:
 /// * `lunatic_spawn_by_index(i32)`
 ///   - receives the index of the function (in the table) to be called indirectly.
-pub fn patch(module: &mut Module) {
-    if let Some(main_function_table) = module.tables.main_function_table().unwrap() {
+/// Fails if the module has more than one function table.
+pub fn patch(module: &mut Module) -> Result<(), Error> {
+    if let Some(main_function_table) = module.tables.main_function_table()? {
         let mut builder = walrus::FunctionBuilder::new(
             &mut module.types,
             &[ValType::I32, ValType::I32, ValType::I32],
@@ -24,4 +28,18 @@ pub fn patch(module: &mut Module) {
         let function = builder.finish(vec![index, argument1, argument2], &mut module.funcs);
         module.exports.add("lunatic_spawn_by_index", function);
     }
+    Ok(())
+}
+
+/// Functions required by the Lunatic stdlib.
+pub struct Stdlib;
+
+impl Patch for Stdlib {
+    fn name(&self) -> &str {
+        "stdlib"
+    }
+
+    fn patch(&self, module: &mut Module) -> Result<(), Error> {
+        patch(module)
+    }
 }
@@ -1,6 +1,6 @@
 use crate::module::LunaticModule;
 
-use super::{FunctionLookup, MemoryChoice, Process};
+use super::{FunctionLookup, MemoryChoice, Process, ProcessConfig};
 
 use anyhow::Result;
 use smol::{future::yield_now, Timer};
@@ -10,13 +10,15 @@ use std::time::{Duration, Instant};
 
 pub struct ProcessState {
     module: LunaticModule,
+    config: ProcessConfig,
     pub processes: HashMapStore<Process>,
 }
 
 impl ProcessState {
-    pub fn new(module: LunaticModule) -> Self {
+    pub fn new(module: LunaticModule, config: ProcessConfig) -> Self {
         Self {
             module,
+            config,
             processes: HashMapStore::new(),
         }
     }
@@ -42,6 +44,28 @@ impl ProcessState {
             self.module.clone(),
             FunctionLookup::TableIndex((index, argument1, argument2)),
             MemoryChoice::New,
+            self.config.for_child(),
+        )
+    }
+
+    // Like `spawn`, but the child yields after `reduction_limit` reductions instead of inheriting
+    // the limit of this process.
+    async fn spawn_with_reduction_limit(
+        &self,
+        index: u32,
+        argument1: u32,
+        argument2: u32,
+        reduction_limit: u32,
+    ) -> Process {
+        let config = ProcessConfig {
+            reduction_limit,
+            ..self.config.for_child()
+        };
+        Process::spawn(
+            self.module.clone(),
+            FunctionLookup::TableIndex((index, argument1, argument2)),
+            MemoryChoice::New,
+            config,
         )
     }
 
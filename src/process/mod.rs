@@ -6,13 +6,28 @@ use async_wormhole::AsyncYielder;
 use lazy_static::lazy_static;
 use smol::{Executor, Task};
 use uptown_funk::{FromWasmU32, ToWasmU32};
+use wasmtime::Trap;
 
 use crate::linker::LunaticLinker;
 use crate::memory::LunaticMemory;
 use crate::module::LunaticModule;
+use crate::networking::{
+    backend::{NativeNetwork, NetworkBackend},
+    policy::NetworkPolicy,
+    TcpListener,
+};
+use crate::normalisation::DEFAULT_REDUCTION_LIMIT;
+use crate::wasi::{
+    clock::{Clock, OsRandom, RandomSource, SystemClock},
+    filesystem::PreopenedDir,
+    stdio::Output,
+    vfs::VirtualDir,
+};
 
 use log::info;
 use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::{future::Future, rc::Rc};
 
 lazy_static! {
@@ -20,7 +35,10 @@ lazy_static! {
     pub static ref EXECUTOR: Executor<'static> = Executor::new();
 }
 
-pub type AsyncYielderCast<'a> = AsyncYielder<'a, Result<()>>;
+// Process ids are unique for the lifetime of the runtime, the first process gets the id 1.
+static NEXT_PROCESS_ID: AtomicU64 = AtomicU64::new(1);
+
+pub type AsyncYielderCast<'a> = AsyncYielder<'a, Result<ProcessOutcome>>;
 
 /// Used to look up a function by name or table index inside of an Instance.
 pub enum FunctionLookup {
@@ -37,6 +55,84 @@ pub enum MemoryChoice {
     New,
 }
 
+/// Capabilities and settings a process is spawned with.
+/// Processes spawned from the guest inherit the configuration of their parent.
+#[derive(Clone)]
+pub struct ProcessConfig {
+    /// Arguments passed to the guest through WASI, the first one is the program name.
+    pub args: Vec<String>,
+    /// Environment variables visible to the guest. The host's environment is not exposed by default.
+    pub env: Vec<(String, String)>,
+    pub network_policy: Arc<NetworkPolicy>,
+    /// Sockets are created through this backend. Use `networking::virtual_network::VirtualNetwork`
+    /// to run processes in an in-memory network.
+    pub network_backend: Arc<dyn NetworkBackend>,
+    /// Host directories the process can access through the WASI filesystem API.
+    pub preopened_dirs: Vec<PreopenedDir>,
+    /// In-memory directories the process can access through the WASI filesystem API, following
+    /// the preopened host directories.
+    pub virtual_dirs: Vec<VirtualDir>,
+    /// Listening sockets handed to the process as WASI file descriptors, following stdio.
+    pub preopened_sockets: Vec<TcpListener>,
+    /// Also hand `preopened_sockets` to processes spawned from the guest. Off by default, so that
+    /// only the first process owns them.
+    pub share_preopened_sockets: bool,
+    /// Source of the WASI clocks. Replace it with a `wasi::clock::VirtualClock` to control time.
+    pub clock: Arc<dyn Clock>,
+    /// Source of WASI `random_get`.
+    pub random: Arc<dyn RandomSource>,
+    /// Destination of everything the guest writes to stdout.
+    pub stdout: Output,
+    /// Destination of everything the guest writes to stderr.
+    pub stderr: Output,
+    /// How many reductions the process can do before it yields to other processes. What a
+    /// reduction is depends on the cost model the module was compiled with. The guest can pick a
+    /// different limit for its children with `lunatic::spawn_with_reduction_limit`.
+    pub reduction_limit: u32,
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        Self {
+            args: Vec::new(),
+            env: Vec::new(),
+            network_policy: Arc::new(NetworkPolicy::default()),
+            network_backend: Arc::new(NativeNetwork),
+            preopened_dirs: Vec::new(),
+            virtual_dirs: Vec::new(),
+            preopened_sockets: Vec::new(),
+            share_preopened_sockets: false,
+            clock: Arc::new(SystemClock::new()),
+            random: Arc::new(OsRandom),
+            stdout: Output::Inherit,
+            stderr: Output::Inherit,
+            reduction_limit: DEFAULT_REDUCTION_LIMIT,
+        }
+    }
+}
+
+impl ProcessConfig {
+    /// The configuration of a process spawned from the guest. Everything is shared with the
+    /// parent, except that each virtual directory gets a tree of its own and preopened sockets
+    /// are only passed on if `share_preopened_sockets` is set.
+    pub fn for_child(&self) -> Self {
+        let preopened_sockets = if self.share_preopened_sockets {
+            self.preopened_sockets.clone()
+        } else {
+            Vec::new()
+        };
+        Self {
+            virtual_dirs: self
+                .virtual_dirs
+                .iter()
+                .map(VirtualDir::for_child)
+                .collect(),
+            preopened_sockets,
+            ..self.clone()
+        }
+    }
+}
+
 /// This structure is captured inside HOST function closures passed to Wasmtime's Linker.
 /// It allows us to expose Lunatic runtime functionalities inside host functions, like
 /// async yields or Instance memory access.
@@ -78,41 +174,82 @@ impl ProcessEnvironment {
     }
 }
 
+/// How a process finished. Traps are reported as errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessOutcome {
+    /// The process function returned.
+    Returned,
+    /// The process exited through WASI's `proc_exit`.
+    Exited(i32),
+}
+
+impl ProcessOutcome {
+    /// Returning from the process function is the same as exiting with code 0.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ProcessOutcome::Returned => 0,
+            ProcessOutcome::Exited(exit_code) => *exit_code,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.exit_code() == 0
+    }
+}
+
 /// A lunatic process represents an actor.
 pub struct Process {
-    task: Task<Result<()>>,
+    id: u64,
+    task: Task<Result<ProcessOutcome>>,
 }
 
 impl Process {
-    pub fn join(self) -> Task<Result<()>> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn join(self) -> Task<Result<ProcessOutcome>> {
         self.task
     }
 
     /// Spawn a new process.
-    pub fn spawn(module: LunaticModule, function: FunctionLookup, memory: MemoryChoice) -> Self {
+    pub fn spawn(
+        module: LunaticModule,
+        function: FunctionLookup,
+        memory: MemoryChoice,
+        config: ProcessConfig,
+    ) -> Self {
+        let id = NEXT_PROCESS_ID.fetch_add(1, Ordering::Relaxed);
         let process = WORMHOLE_POOL.with_tls(
             [&wasmtime_runtime::traphandlers::tls::PTR],
             move |yielder| {
                 let yielder_ptr = &yielder as *const AsyncYielderCast as usize;
 
-                let linker = LunaticLinker::new(module, yielder_ptr, memory)?;
+                let linker = LunaticLinker::new(module, id, yielder_ptr, memory, config)?;
                 let instance = linker.instance()?;
 
-                match function {
+                let result = match function {
                     FunctionLookup::Name(name) => {
                         let func = instance.get_func(name).unwrap();
                         // Measure how long the function takes for named functions.
                         let performance_timer = std::time::Instant::now();
-                        func.call(&[])?;
+                        let result = func.call(&[]);
                         info!(target: "performance", "Process {} finished in {} ms.", name, performance_timer.elapsed().as_millis());
+                        result
                     }
                     FunctionLookup::TableIndex((index, argument1, argument2)) => {
                         let func = instance.get_func("lunatic_spawn_by_index").unwrap();
-                        func.call(&[(index as i32).into(), (argument1 as i32).into(), (argument2 as i32).into()])?;
+                        func.call(&[(index as i32).into(), (argument1 as i32).into(), (argument2 as i32).into()])
                     }
-                }
+                };
 
-                Ok(())
+                match result {
+                    Ok(_) => Ok(ProcessOutcome::Returned),
+                    Err(error) => match error.downcast_ref::<Trap>().and_then(Trap::i32_exit_status) {
+                        Some(exit_code) => Ok(ProcessOutcome::Exited(exit_code)),
+                        None => Err(error),
+                    },
+                }
             },
         );
 
@@ -123,7 +260,7 @@ impl Process {
             result
         });
 
-        Self { task }
+        Self { id, task }
     }
 }
 
@@ -156,3 +293,29 @@ impl<'a> FromWasmU32<'a> for Process {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use smol::future;
+
+    use super::*;
+    use crate::networking::virtual_network::VirtualNetwork;
+
+    #[test]
+    fn children_only_get_shared_sockets() {
+        let network = Arc::new(VirtualNetwork::new());
+        let mut config = ProcessConfig {
+            network_policy: Arc::new(NetworkPolicy::allow_all()),
+            network_backend: network.clone(),
+            ..ProcessConfig::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:8080", &config.network_policy, &*network);
+        config
+            .preopened_sockets
+            .push(future::block_on(listener).unwrap());
+
+        assert!(config.for_child().preopened_sockets.is_empty());
+        config.share_preopened_sockets = true;
+        assert_eq!(config.for_child().preopened_sockets.len(), 1);
+    }
+}
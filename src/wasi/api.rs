@@ -1,29 +1,270 @@
+use super::clock::{Clock, RandomSource};
+use super::descriptors::{Descriptor, FdTable};
+use super::filesystem::{read_file, write_file, Filestat};
+use super::poll::{first_ready, Event, Subscription, SubscriptionKind};
+use super::stdio::{read_stdin, stdin_readable, HostStream, OutputWriter};
 use super::types::*;
+use super::vfs;
+use crate::networking::TcpStream;
+use crate::process::ProcessConfig;
 
 use anyhow::Result;
 use uptown_funk::{host_functions, FromWasmU32};
 
 use log::trace;
+use smol::future::{self, Boxed};
 use std::{
-    io::{self, IoSlice, IoSliceMut, Read, Write},
+    cell::RefCell,
+    collections::HashMap,
+    convert::TryInto,
+    fs,
+    future::Future,
+    io::{self, IoSlice, IoSliceMut, Seek, SeekFrom},
+    net::Shutdown,
+    rc::Rc,
+    sync::Arc,
 };
 
-lazy_static::lazy_static! {
-    static ref ENV : WasiEnvVars = WasiEnvVars::new(std::env::vars());
+/// The WASI state of a process. It's shared between the `wasi_snapshot_preview1` and
+/// `wasi_unstable` namespaces, see `share`.
+pub struct WasiState {
+    args: Rc<WasiStrings>,
+    env: Rc<WasiStrings>,
+    // A process is suspended as a whole while a host function waits, so borrows held across
+    // `await`s can't conflict.
+    fds: Rc<RefCell<FdTable>>,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn RandomSource>,
+    // Connections accepted by `poll_oneoff` while waiting for a listener to become ready.
+    // They are handed out by the next `sock_accept` call on the listener.
+    accepted: Rc<RefCell<HashMap<u32, TcpStream>>>,
 }
 
-pub struct WasiState {}
+// Resolves once a `poll_oneoff` subscription is ready. Waiting on a listener accepts a connection.
+type PendingEvent = Boxed<(Event, Option<(u32, TcpStream)>)>;
 
 impl WasiState {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(config: &ProcessConfig, process_id: u64) -> Self {
+        let stdout = OutputWriter::new(config.stdout.clone(), HostStream::Stdout, process_id);
+        let stderr = OutputWriter::new(config.stderr.clone(), HostStream::Stderr, process_id);
+        Self {
+            args: Rc::new(WasiStrings::new(config.args.iter().cloned())),
+            env: Rc::new(WasiStrings::env_vars(config.env.iter().cloned())),
+            fds: Rc::new(RefCell::new(FdTable::new(
+                stdout,
+                stderr,
+                &config.preopened_dirs,
+                &config.virtual_dirs,
+                &config.preopened_sockets,
+            ))),
+            clock: config.clock.clone(),
+            random: config.random.clone(),
+            accepted: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Returns a handle to the same state, e.g. file descriptors opened through one handle are
+    /// visible through the other.
+    pub fn share(&self) -> Self {
+        Self {
+            args: self.args.clone(),
+            env: self.env.clone(),
+            fds: self.fds.clone(),
+            clock: self.clock.clone(),
+            random: self.random.clone(),
+            accepted: self.accepted.clone(),
+        }
+    }
+
+    // Returns the connected socket behind `fd` if it has the `rights`, or the errno describing
+    // why it can't be used. The handle is a clone, so that the fd table isn't borrowed while
+    // waiting on it.
+    fn tcp_stream(&self, fd: u32, rights: u64) -> Result<TcpStream, u32> {
+        match self.fds.borrow().get_with_rights(fd, rights) {
+            Ok(Descriptor::TcpStream(stream)) => Ok(stream.clone()),
+            Ok(Descriptor::TcpListener(_)) => Err(WASI_ENOTCONN),
+            Ok(_) => Err(WASI_ENOTSOCK),
+            Err(errno) => Err(errno),
+        }
+    }
+
+    pub(super) fn fd_filestat(&self, fd: u32) -> Result<Filestat, u32> {
+        let metadata = match self
+            .fds
+            .borrow()
+            .get_with_rights(fd, WASI_RIGHT_FD_FILESTAT_GET)
+        {
+            Ok(Descriptor::File(file)) => file.metadata(),
+            Ok(Descriptor::Directory(directory)) => fs::metadata(directory.path()),
+            Ok(Descriptor::VirtualFile(file)) => return file.filestat(),
+            Ok(Descriptor::VirtualDirectory(directory)) => return directory.filestat(),
+            Ok(descriptor) => return Ok(Filestat::of_type(descriptor.filetype())),
+            Err(errno) => return Err(errno),
+        };
+        match metadata {
+            Ok(metadata) => Ok(Filestat::from_metadata(&metadata)),
+            Err(err) => Err(io_error_to_errno(&err)),
+        }
+    }
+
+    pub(super) async fn path_filestat(
+        &self,
+        dirfd: u32,
+        flags: u32,
+        path: &str,
+    ) -> Result<Filestat, u32> {
+        match self
+            .fds
+            .borrow()
+            .get_with_rights(dirfd, WASI_RIGHT_PATH_FILESTAT_GET)
+        {
+            Ok(Descriptor::Directory(directory)) => {
+                let follow = flags & WASI_LOOKUP_SYMLINK_FOLLOW != 0;
+                let metadata = directory.metadata(path, follow).await?;
+                Ok(Filestat::from_metadata(&metadata))
+            }
+            Ok(Descriptor::VirtualDirectory(directory)) => {
+                directory.path_filestat(&directory.resolve(path)?)
+            }
+            Ok(_) => Err(WASI_ENOTDIR),
+            Err(errno) => Err(errno),
+        }
+    }
+
+    // Waits until at least one of the subscriptions is ready and returns the ready events.
+    // The process is suspended while waiting. Files are always reported as ready.
+    pub(super) async fn poll(&mut self, subscriptions: Vec<Subscription>) -> Vec<Event> {
+        let mut ready = Vec::new();
+        let mut pending = Vec::new();
+        for subscription in subscriptions {
+            match self.poll_subscription(subscription) {
+                Ok(event) => ready.push(event),
+                Err(future) => pending.push(future),
+            }
+        }
+
+        if ready.is_empty() {
+            let (event, accepted) = first_ready(pending).await;
+            if let Some((fd, stream)) = accepted {
+                self.accepted.borrow_mut().insert(fd, stream);
+            }
+            ready.push(event);
+        }
+        ready
+    }
+
+    // Returns the event if the subscription is already ready, otherwise a future resolving to it.
+    fn poll_subscription(&mut self, subscription: Subscription) -> Result<Event, PendingEvent> {
+        match subscription.kind {
+            SubscriptionKind::Clock {
+                id,
+                timeout,
+                absolute,
+            } => {
+                let now = match id {
+                    WASI_CLOCK_REALTIME => self.clock.realtime(),
+                    WASI_CLOCK_MONOTONIC => self.clock.monotonic(),
+                    _ => return Ok(Event::error(&subscription, WASI_EINVAL)),
+                };
+                let remaining = if absolute {
+                    timeout.saturating_sub(now)
+                } else {
+                    timeout
+                };
+                if remaining == 0 {
+                    return Ok(Event::ready(&subscription, 0, false));
+                }
+                // Both clocks advance at the same rate, so the wait can always use the monotonic one.
+                let deadline = self.clock.monotonic().saturating_add(remaining);
+                let sleep = self.clock.sleep_until(deadline);
+                Err(Box::pin(async move {
+                    sleep.await;
+                    (Event::ready(&subscription, 0, false), None)
+                }))
+            }
+            SubscriptionKind::FdRead(fd) => match self.fds.borrow_mut().get_mut(fd) {
+                Some(Descriptor::Stdin) => Err(Box::pin(async move {
+                    let event = match stdin_readable().await {
+                        Ok(readable) => Event::ready(&subscription, readable as u64, readable == 0),
+                        Err(err) => Event::error(&subscription, io_error_to_errno(&err)),
+                    };
+                    (event, None)
+                })),
+                Some(Descriptor::File(file)) => {
+                    let remaining = match (file.metadata(), file.seek(SeekFrom::Current(0))) {
+                        (Ok(metadata), Ok(position)) => metadata.len().saturating_sub(position),
+                        _ => 0,
+                    };
+                    Ok(Event::ready(&subscription, remaining, false))
+                }
+                Some(Descriptor::VirtualFile(file)) => {
+                    Ok(Event::ready(&subscription, file.remaining(), false))
+                }
+                Some(Descriptor::TcpStream(stream)) => {
+                    let stream = stream.clone();
+                    Err(Box::pin(async move {
+                        let event = match stream.readable().await {
+                            Ok(readable) => {
+                                Event::ready(&subscription, readable as u64, readable == 0)
+                            }
+                            Err(err) => Event::error(&subscription, io_error_to_errno(&err)),
+                        };
+                        (event, None)
+                    }))
+                }
+                Some(Descriptor::TcpListener(_)) if self.accepted.borrow().contains_key(&fd) => {
+                    Ok(Event::ready(&subscription, 1, false))
+                }
+                Some(Descriptor::TcpListener(listener)) => {
+                    let listener = listener.clone();
+                    Err(Box::pin(async move {
+                        match listener.accept().await {
+                            Ok(stream) => {
+                                (Event::ready(&subscription, 1, false), Some((fd, stream)))
+                            }
+                            Err(err) => {
+                                (Event::error(&subscription, io_error_to_errno(&err)), None)
+                            }
+                        }
+                    }))
+                }
+                _ => Ok(Event::error(&subscription, WASI_EBADF)),
+            },
+            // Writes are always reported as ready, buffering is left to the host.
+            SubscriptionKind::FdWrite(fd) => match self.fds.borrow().get(fd) {
+                Some(Descriptor::Stdout(_))
+                | Some(Descriptor::Stderr(_))
+                | Some(Descriptor::File(_))
+                | Some(Descriptor::VirtualFile(_))
+                | Some(Descriptor::TcpStream(_)) => Ok(Event::ready(&subscription, 0, false)),
+                _ => Ok(Event::error(&subscription, WASI_EBADF)),
+            },
+        }
+    }
+}
+
+// Waits for the IO `future` to complete. For non-blocking descriptors it's only polled once and
+// fails with `WouldBlock` (`WASI_EAGAIN`) if it isn't ready yet.
+async fn block_unless<T>(
+    nonblocking: bool,
+    future: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    if nonblocking {
+        match future::poll_once(future).await {
+            Some(result) => result,
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    } else {
+        future.await
     }
 }
-struct ExitCode {}
+
+pub(super) struct ExitCode {}
 
 impl<'a> FromWasmU32<'a> for ExitCode {
     type State = WasiState;
 
+    // Exiting is implemented as a trap, so that no guest code runs after `proc_exit`.
     fn from_u32<I>(
         _state: &mut Self::State,
         _instance_environment: &'a I,
@@ -33,86 +274,539 @@ impl<'a> FromWasmU32<'a> for ExitCode {
         Self: Sized,
         I: uptown_funk::InstanceEnvironment,
     {
-        Err(uptown_funk::Trap::new(format!(
-            "proc_exit({}) called",
-            exit_code
-        )))
+        Err(uptown_funk::Trap::exit(exit_code as i32))
     }
 }
 
-type Ptr<'a, T> = uptown_funk::Pointer<'a, WasiState, T>;
+pub(super) type Ptr<'a, T> = uptown_funk::Pointer<'a, WasiState, T>;
 
 #[host_functions(namespace = "wasi_snapshot_preview1")]
 impl WasiState {
-    fn proc_exit(&self, _exit_code: ExitCode) {}
+    pub(super) fn proc_exit(&self, _exit_code: ExitCode) {}
 
-    fn fd_write(&self, fd: u32, ciovs: &[IoSlice<'_>]) -> (u32, u32) {
-        match fd {
+    pub(super) async fn fd_write<'a>(
+        &'a mut self,
+        fd: u32,
+        ciovs: &'a [IoSlice<'a>],
+    ) -> (u32, u32) {
+        let nonblocking = self.fds.borrow().is_nonblocking(fd);
+        match self
+            .fds
+            .borrow_mut()
+            .get_mut_with_rights(fd, WASI_RIGHT_FD_WRITE)
+        {
             // Stdin not supported as write destination
-            0 => (WASI_EINVAL, 0),
-            1 => {
-                let written = io::stdout().write_vectored(ciovs).unwrap();
-                (WASI_ESUCCESS, written as u32)
+            Ok(Descriptor::Stdin) => (WASI_EINVAL, 0),
+            Ok(Descriptor::Stdout(output)) | Ok(Descriptor::Stderr(output)) => {
+                match output.write_vectored(ciovs).await {
+                    Ok(written) => (WASI_ESUCCESS, written as u32),
+                    Err(err) => (io_error_to_errno(&err), 0),
+                }
             }
-            2 => {
-                let written = io::stderr().write_vectored(ciovs).unwrap();
-                (WASI_ESUCCESS, written as u32)
+            Ok(Descriptor::File(file)) => match write_file(file, ciovs).await {
+                Ok(written) => (WASI_ESUCCESS, written as u32),
+                Err(err) => (io_error_to_errno(&err), 0),
+            },
+            Ok(Descriptor::VirtualFile(file)) => match file.write_vectored(ciovs) {
+                Ok(written) => (WASI_ESUCCESS, written as u32),
+                Err(errno) => (errno, 0),
+            },
+            Ok(Descriptor::Directory(_)) | Ok(Descriptor::VirtualDirectory(_)) => (WASI_EISDIR, 0),
+            Ok(Descriptor::TcpStream(stream)) => {
+                match block_unless(nonblocking, stream.write_vectored(ciovs)).await {
+                    Ok(written) => (WASI_ESUCCESS, written as u32),
+                    Err(err) => (io_error_to_errno(&err), 0),
+                }
             }
-            _ => panic!("Unsupported wasi write destination"),
+            Ok(Descriptor::TcpListener(_)) => (WASI_ENOTCONN, 0),
+            Err(errno) => (errno, 0),
         }
     }
 
-    fn fd_read(&self, fd: u32, iovs: &mut [IoSliceMut<'_>]) -> (u32, u32) {
-        match fd {
+    pub(super) async fn fd_read<'a>(
+        &'a mut self,
+        fd: u32,
+        iovs: &'a mut [IoSliceMut<'a>],
+    ) -> (u32, u32) {
+        let nonblocking = self.fds.borrow().is_nonblocking(fd);
+        match self
+            .fds
+            .borrow_mut()
+            .get_mut_with_rights(fd, WASI_RIGHT_FD_READ)
+        {
             // Stdout & stderr not supported as read destination
-            1 | 2 => (WASI_EINVAL, 0),
-            0 => {
-                let written = io::stdin().read_vectored(iovs).unwrap();
-                (WASI_ESUCCESS, written as u32)
+            Ok(Descriptor::Stdout(_)) | Ok(Descriptor::Stderr(_)) => (WASI_EINVAL, 0),
+            Ok(Descriptor::Stdin) => match block_unless(nonblocking, read_stdin(iovs)).await {
+                Ok(read) => (WASI_ESUCCESS, read as u32),
+                Err(err) => (io_error_to_errno(&err), 0),
+            },
+            Ok(Descriptor::File(file)) => match read_file(file, iovs).await {
+                Ok(read) => (WASI_ESUCCESS, read as u32),
+                Err(err) => (io_error_to_errno(&err), 0),
+            },
+            Ok(Descriptor::VirtualFile(file)) => match file.read_vectored(iovs) {
+                Ok(read) => (WASI_ESUCCESS, read as u32),
+                Err(errno) => (errno, 0),
+            },
+            Ok(Descriptor::Directory(_)) | Ok(Descriptor::VirtualDirectory(_)) => (WASI_EISDIR, 0),
+            Ok(Descriptor::TcpStream(stream)) => {
+                match block_unless(nonblocking, stream.read_vectored(iovs)).await {
+                    Ok(read) => (WASI_ESUCCESS, read as u32),
+                    Err(err) => (io_error_to_errno(&err), 0),
+                }
             }
-            _ => panic!("Unsupported wasi read destination"),
+            Ok(Descriptor::TcpListener(_)) => (WASI_ENOTCONN, 0),
+            Err(errno) => (errno, 0),
         }
     }
 
-    fn path_open(
-        &self,
-        _a: u32,
-        _b: u32,
-        _c: u32,
-        _d: u32,
-        _e: u32,
-        _f: i64,
-        _g: i64,
-        _h: u32,
+    // Opens `path` relative to the directory `dirfd`. Only the read and write rights are taken into
+    // account, the inheriting rights are ignored. Of the fd flags only append and non-blocking
+    // mode are supported.
+    pub(super) async fn path_open(
+        &mut self,
+        dirfd: u32,
+        dirflags: u32,
+        path: &str,
+        oflags: u32,
+        fs_rights_base: i64,
+        _fs_rights_inheriting: i64,
+        fdflags: u32,
     ) -> (u32, u32) {
-        (0, 0)
+        trace!("wasi_snapshot_preview1:path_open({}, {})", dirfd, path);
+        let requested = fs_rights_base as u64;
+        let append = fdflags & WASI_FDFLAG_APPEND != 0;
+        let truncate = oflags & WASI_O_TRUNC != 0;
+        let write = requested & WASI_RIGHT_FD_WRITE != 0 || append || truncate;
+        let read = requested & WASI_RIGHT_FD_READ != 0 || !write;
+        let create = oflags & WASI_O_CREAT != 0;
+        let exclusive = oflags & WASI_O_EXCL != 0;
+        let directory_only = oflags & WASI_O_DIRECTORY != 0;
+        let follow = dirflags & WASI_LOOKUP_SYMLINK_FOLLOW != 0;
+        let options = vfs::OpenOptions {
+            read,
+            write: write && !append,
+            append,
+            truncate,
+            create: create && !exclusive,
+            create_new: create && exclusive,
+        };
+        let dir_rights = if create {
+            WASI_RIGHT_PATH_OPEN | WASI_RIGHT_PATH_CREATE_FILE
+        } else {
+            WASI_RIGHT_PATH_OPEN
+        };
+
+        let descriptor = match self.fds.borrow().get_with_rights(dirfd, dir_rights) {
+            Ok(Descriptor::Directory(directory)) => {
+                let metadata = directory.metadata(path, follow).await;
+                let is_dir = matches!(&metadata, Ok(metadata) if metadata.is_dir());
+                if directory_only || (!create && is_dir) {
+                    match metadata {
+                        Ok(metadata) if metadata.is_dir() => {}
+                        // Like `O_NOFOLLOW`
+                        Ok(metadata) if metadata.file_type().is_symlink() => {
+                            return (WASI_ELOOP, 0)
+                        }
+                        Ok(_) => return (WASI_ENOTDIR, 0),
+                        Err(errno) => return (errno, 0),
+                    }
+                    match directory.open_dir(path, follow).await {
+                        Ok(directory) => Descriptor::Directory(directory),
+                        Err(errno) => return (errno, 0),
+                    }
+                } else {
+                    match directory.open_file(path, options, follow).await {
+                        Ok(file) => Descriptor::File(file),
+                        Err(errno) => return (errno, 0),
+                    }
+                }
+            }
+            Ok(Descriptor::VirtualDirectory(directory)) => {
+                let path = match directory.resolve(path) {
+                    Ok(path) => path,
+                    Err(errno) => return (errno, 0),
+                };
+                let opened = if directory_only || (!create && directory.is_dir(&path)) {
+                    directory.open_dir(path).map(Descriptor::VirtualDirectory)
+                } else {
+                    directory
+                        .open_file(path, options)
+                        .map(Descriptor::VirtualFile)
+                };
+                match opened {
+                    Ok(descriptor) => descriptor,
+                    Err(errno) => return (errno, 0),
+                }
+            }
+            Ok(_) => return (WASI_ENOTDIR, 0),
+            Err(errno) => return (errno, 0),
+        };
+
+        let flags = fdflags & (WASI_FDFLAG_APPEND | WASI_FDFLAG_NONBLOCK);
+        // Limited to the rights supported for the descriptor type by the fd table.
+        let mut rights = u64::MAX;
+        if !read {
+            rights &= !WASI_RIGHT_FD_READ;
+        }
+        if !write {
+            rights &= !WASI_RIGHT_FD_WRITE;
+        }
+        (
+            WASI_ESUCCESS,
+            self.fds.borrow_mut().add_with(descriptor, rights, flags),
+        )
     }
 
-    fn fd_close(&self, fd: u32) -> u32 {
+    pub(super) fn fd_close(&mut self, fd: u32) -> u32 {
         trace!("wasi_snapshot_preview1:fd_close({})", fd);
-        WASI_ESUCCESS
+        self.accepted.borrow_mut().remove(&fd);
+        match self.fds.borrow_mut().remove(fd) {
+            Some(_descriptor) => WASI_ESUCCESS,
+            None => WASI_EBADF,
+        }
     }
 
-    fn fd_prestat_get(&self, _fd: u32, _prestat_ptr: u32) -> u32 {
-        WASI_EBADF
+    pub(super) fn fd_seek(&mut self, fd: u32, offset: i64, whence: u32) -> (u32, u64) {
+        let position = match whence {
+            WASI_WHENCE_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            WASI_WHENCE_CUR => SeekFrom::Current(offset),
+            WASI_WHENCE_END => SeekFrom::End(offset),
+            _ => return (WASI_EINVAL, 0),
+        };
+        // Only asking for the current offset is allowed with just the tell right.
+        let rights = match position {
+            SeekFrom::Current(0) => WASI_RIGHT_FD_TELL,
+            _ => WASI_RIGHT_FD_SEEK,
+        };
+        match self.fds.borrow_mut().get_mut_with_rights(fd, rights) {
+            Ok(Descriptor::File(file)) => match file.seek(position) {
+                Ok(offset) => (WASI_ESUCCESS, offset),
+                Err(err) => (io_error_to_errno(&err), 0),
+            },
+            Ok(Descriptor::VirtualFile(file)) => match file.seek(position) {
+                Ok(offset) => (WASI_ESUCCESS, offset),
+                Err(errno) => (errno, 0),
+            },
+            Ok(Descriptor::Directory(_)) | Ok(Descriptor::VirtualDirectory(_)) => (WASI_EISDIR, 0),
+            Ok(_) => (WASI_ESPIPE, 0),
+            Err(errno) => (errno, 0),
+        }
     }
 
-    fn fd_prestat_dir_name(&self, _fd: u32, _path: &str) -> u32 {
-        WASI_ESUCCESS
+    pub(super) fn fd_fdstat_get(&self, fd: u32, buf: Ptr<u8>) -> u32 {
+        match self.fds.borrow().fdstat(fd) {
+            Some(fdstat) => {
+                buf.copy_slice(&fdstat);
+                WASI_ESUCCESS
+            }
+            None => WASI_EBADF,
+        }
     }
 
-    fn environ_sizes_get(&self, mut var_count: Ptr<u32>, mut total_bytes: Ptr<u32>) -> u32 {
-        var_count.set(&ENV.len());
-        total_bytes.set(&ENV.total_bytes());
-        WASI_ESUCCESS
+    pub(super) fn fd_fdstat_set_flags(&mut self, fd: u32, flags: u32) -> u32 {
+        match self.fds.borrow_mut().set_flags(fd, flags) {
+            Ok(()) => WASI_ESUCCESS,
+            Err(errno) => errno,
+        }
     }
 
-    fn environ_get<'a>(&self, mut environ: Ptr<Ptr<'a, u8>>, mut environ_buf: Ptr<'a, u8>) -> u32 {
-        for kv in ENV.iter() {
-            environ.set(&environ_buf);
-            environ_buf = environ_buf.copy_slice(&kv).unwrap();
-            environ = environ.next().unwrap();
+    pub(super) fn fd_filestat_get(&self, fd: u32, buf: Ptr<u8>) -> u32 {
+        match self.fd_filestat(fd) {
+            Ok(filestat) => {
+                buf.copy_slice(&filestat.to_bytes());
+                WASI_ESUCCESS
+            }
+            Err(errno) => errno,
         }
+    }
+
+    pub(super) async fn path_filestat_get(
+        &self,
+        dirfd: u32,
+        flags: u32,
+        path: &str,
+        buf: Ptr<'_, u8>,
+    ) -> u32 {
+        match self.path_filestat(dirfd, flags, path).await {
+            Ok(filestat) => {
+                buf.copy_slice(&filestat.to_bytes());
+                WASI_ESUCCESS
+            }
+            Err(errno) => errno,
+        }
+    }
+
+    pub(super) async fn fd_readdir<'a>(
+        &'a self,
+        fd: u32,
+        buf: &'a mut [u8],
+        cookie: i64,
+    ) -> (u32, u32) {
+        match self.fds.borrow().get_with_rights(fd, WASI_RIGHT_FD_READDIR) {
+            Ok(Descriptor::Directory(directory)) => {
+                match directory.read_entries(buf, cookie as u64).await {
+                    Ok(used) => (WASI_ESUCCESS, used as u32),
+                    Err(err) => (io_error_to_errno(&err), 0),
+                }
+            }
+            Ok(Descriptor::VirtualDirectory(directory)) => {
+                match directory.read_entries(buf, cookie as u64) {
+                    Ok(used) => (WASI_ESUCCESS, used as u32),
+                    Err(errno) => (errno, 0),
+                }
+            }
+            Ok(_) => (WASI_ENOTDIR, 0),
+            Err(errno) => (errno, 0),
+        }
+    }
+
+    // Only preopened directories have a prestat, all other fds return `WASI_EBADF`.
+    pub(super) fn fd_prestat_get(&self, fd: u32, mut prestat: Ptr<u32>) -> u32 {
+        match self
+            .fds
+            .borrow()
+            .get(fd)
+            .and_then(Descriptor::preopened_path)
+        {
+            Some(guest_path) => {
+                // Tag 0 (directory) followed by the length of its name
+                prestat.set(&0);
+                match prestat.next() {
+                    Some(mut name_len) => name_len.set(&(guest_path.len() as u32)),
+                    None => return WASI_EFAULT,
+                }
+                WASI_ESUCCESS
+            }
+            None => WASI_EBADF,
+        }
+    }
+
+    pub(super) fn fd_prestat_dir_name(&self, fd: u32, path: &mut [u8]) -> u32 {
+        let fds = self.fds.borrow();
+        let guest_path = match fds.get(fd).and_then(Descriptor::preopened_path) {
+            Some(guest_path) => guest_path.as_bytes(),
+            None => return WASI_EBADF,
+        };
+        match path.get_mut(..guest_path.len()) {
+            Some(destination) => {
+                destination.copy_from_slice(guest_path);
+                WASI_ESUCCESS
+            }
+            None => WASI_ENAMETOOLONG,
+        }
+    }
+
+    pub(super) fn args_sizes_get(&self, arg_count: Ptr<u32>, total_bytes: Ptr<u32>) -> u32 {
+        write_sizes(&self.args, arg_count, total_bytes)
+    }
+
+    pub(super) fn args_get<'a>(&self, argv: Ptr<Ptr<'a, u8>>, argv_buf: Ptr<'a, u8>) -> u32 {
+        write_strings(&self.args, argv, argv_buf)
+    }
+
+    pub(super) fn environ_sizes_get(&self, var_count: Ptr<u32>, total_bytes: Ptr<u32>) -> u32 {
+        write_sizes(&self.env, var_count, total_bytes)
+    }
+
+    pub(super) fn environ_get<'a>(
+        &self,
+        environ: Ptr<Ptr<'a, u8>>,
+        environ_buf: Ptr<'a, u8>,
+    ) -> u32 {
+        write_strings(&self.env, environ, environ_buf)
+    }
+
+    // Sockets are only received through preopened listeners (`--tcp-listen`) and `sock_accept`.
+
+    // Accepts a connection on a preopened listener and returns its fd. The `flags` for the new fd
+    // are ignored. The process is suspended until a connection arrives, unless the listener is
+    // nonblocking, then EAGAIN is returned.
+    async fn sock_accept(&mut self, fd: u32, _flags: u32) -> (u32, u32) {
+        let listener = match self
+            .fds
+            .borrow()
+            .get_with_rights(fd, WASI_RIGHT_SOCK_ACCEPT)
+        {
+            Ok(Descriptor::TcpListener(listener)) => listener.clone(),
+            Ok(Descriptor::TcpStream(_)) => return (WASI_EINVAL, 0),
+            Ok(_) => return (WASI_ENOTSOCK, 0),
+            Err(errno) => return (errno, 0),
+        };
+        if let Some(stream) = self.accepted.borrow_mut().remove(&fd) {
+            return (
+                WASI_ESUCCESS,
+                self.fds.borrow_mut().add(Descriptor::TcpStream(stream)),
+            );
+        }
+        let nonblocking = self.fds.borrow().is_nonblocking(fd);
+        match block_unless(nonblocking, listener.accept()).await {
+            Ok(stream) => (
+                WASI_ESUCCESS,
+                self.fds.borrow_mut().add(Descriptor::TcpStream(stream)),
+            ),
+            Err(err) => (io_error_to_errno(&err), 0),
+        }
+    }
+
+    // Receive flags (peeking and waiting for all data) are not supported.
+    pub(super) async fn sock_recv<'a>(
+        &'a mut self,
+        fd: u32,
+        ri_data: &'a mut [IoSliceMut<'a>],
+        ri_flags: u32,
+        mut ro_datalen: Ptr<'a, u32>,
+        mut ro_flags: Ptr<'a, u16>,
+    ) -> u32 {
+        if ri_flags != 0 {
+            return WASI_ENOTSUP;
+        }
+        let nonblocking = self.fds.borrow().is_nonblocking(fd);
+        let mut stream = match self.tcp_stream(fd, WASI_RIGHT_FD_READ) {
+            Ok(stream) => stream,
+            Err(errno) => return errno,
+        };
+        match block_unless(nonblocking, stream.read_vectored(ri_data)).await {
+            Ok(read) => {
+                ro_datalen.set(&(read as u32));
+                ro_flags.set(&0);
+                WASI_ESUCCESS
+            }
+            Err(err) => io_error_to_errno(&err),
+        }
+    }
+
+    pub(super) async fn sock_send<'a>(
+        &'a mut self,
+        fd: u32,
+        si_data: &'a [IoSlice<'a>],
+        _si_flags: u32,
+    ) -> (u32, u32) {
+        let nonblocking = self.fds.borrow().is_nonblocking(fd);
+        let mut stream = match self.tcp_stream(fd, WASI_RIGHT_FD_WRITE) {
+            Ok(stream) => stream,
+            Err(errno) => return (errno, 0),
+        };
+        match block_unless(nonblocking, stream.write_vectored(si_data)).await {
+            Ok(written) => (WASI_ESUCCESS, written as u32),
+            Err(err) => (io_error_to_errno(&err), 0),
+        }
+    }
+
+    pub(super) fn sock_shutdown(&mut self, fd: u32, how: u32) -> u32 {
+        let how = match how {
+            WASI_SHUT_RD => Shutdown::Read,
+            WASI_SHUT_WR => Shutdown::Write,
+            how if how == WASI_SHUT_RD | WASI_SHUT_WR => Shutdown::Both,
+            _ => return WASI_EINVAL,
+        };
+        match self.tcp_stream(fd, WASI_RIGHT_SOCK_SHUTDOWN) {
+            Ok(stream) => match stream.shutdown(how) {
+                Ok(()) => WASI_ESUCCESS,
+                Err(err) => io_error_to_errno(&err),
+            },
+            Err(errno) => errno,
+        }
+    }
+
+    // CPU time clocks are not supported, because processes can move between threads.
+    pub(super) fn clock_res_get(&self, id: u32) -> (u32, u64) {
+        match id {
+            WASI_CLOCK_REALTIME | WASI_CLOCK_MONOTONIC => (WASI_ESUCCESS, self.clock.resolution()),
+            WASI_CLOCK_PROCESS_CPUTIME_ID | WASI_CLOCK_THREAD_CPUTIME_ID => (WASI_ENOTSUP, 0),
+            _ => (WASI_EINVAL, 0),
+        }
+    }
+
+    // The requested precision is ignored.
+    pub(super) fn clock_time_get(&self, id: u32, _precision: i64) -> (u32, u64) {
+        match id {
+            WASI_CLOCK_REALTIME => (WASI_ESUCCESS, self.clock.realtime()),
+            WASI_CLOCK_MONOTONIC => (WASI_ESUCCESS, self.clock.monotonic()),
+            WASI_CLOCK_PROCESS_CPUTIME_ID | WASI_CLOCK_THREAD_CPUTIME_ID => (WASI_ENOTSUP, 0),
+            _ => (WASI_EINVAL, 0),
+        }
+    }
+
+    pub(super) fn random_get(&self, buf: &mut [u8]) -> u32 {
+        self.random.fill(buf);
         WASI_ESUCCESS
     }
+
+    // Waits until at least one of the subscriptions is ready and writes the events into `events`.
+    async fn poll_oneoff(
+        &mut self,
+        subscriptions: Ptr<'_, u8>,
+        events: Ptr<'_, u8>,
+        nsubscriptions: u32,
+    ) -> (u32, u32) {
+        let size = nsubscriptions as usize * WASI_SUBSCRIPTION_SIZE;
+        let subscriptions = match subscriptions.slice(size) {
+            Some(subscriptions) if size > 0 => subscriptions,
+            Some(_) => return (WASI_EINVAL, 0),
+            None => return (WASI_EFAULT, 0),
+        };
+        // Checked up front, there can be up to one event per subscription.
+        if events
+            .slice(nsubscriptions as usize * WASI_EVENT_SIZE)
+            .is_none()
+        {
+            return (WASI_EFAULT, 0);
+        }
+        let subscriptions: Option<Vec<Subscription>> = subscriptions
+            .chunks(WASI_SUBSCRIPTION_SIZE)
+            .map(|bytes| Subscription::parse(bytes.try_into().unwrap()))
+            .collect();
+        let subscriptions = match subscriptions {
+            Some(subscriptions) => subscriptions,
+            None => return (WASI_EINVAL, 0),
+        };
+
+        let ready = self.poll(subscriptions).await;
+        let bytes: Vec<u8> = ready
+            .iter()
+            .flat_map(|event| event.to_bytes().to_vec())
+            .collect();
+        // Can't go out of bounds, the buffer was checked before waiting.
+        events.copy_slice(&bytes);
+        (WASI_ESUCCESS, ready.len() as u32)
+    }
+}
+
+fn write_sizes(strings: &WasiStrings, mut count: Ptr<u32>, mut total_bytes: Ptr<u32>) -> u32 {
+    if !count.fits(1) || !total_bytes.fits(1) {
+        return WASI_EFAULT;
+    }
+    count.set(&strings.len());
+    total_bytes.set(&strings.total_bytes());
+    WASI_ESUCCESS
+}
+
+// Writes the strings into `buffer` and a pointer to each of them into `pointers`.
+fn write_strings<'a>(
+    strings: &WasiStrings,
+    mut pointers: Ptr<Ptr<'a, u8>>,
+    mut buffer: Ptr<'a, u8>,
+) -> u32 {
+    if !pointers.fits(strings.len() as usize)
+        || buffer.slice(strings.total_bytes() as usize).is_none()
+    {
+        return WASI_EFAULT;
+    }
+    for (index, string) in strings.iter().enumerate() {
+        pointers.set(&buffer);
+        // The last string may end right at the end of the memory, there is no next position.
+        if index + 1 == strings.len() as usize {
+            buffer.copy_slice(string);
+            break;
+        }
+        match (buffer.copy_slice(string), pointers.next()) {
+            (Some(next_buffer), Some(next_pointer)) => {
+                buffer = next_buffer;
+                pointers = next_pointer;
+            }
+            _ => return WASI_EFAULT,
+        }
+    }
+    WASI_ESUCCESS
 }
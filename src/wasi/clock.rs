@@ -0,0 +1,179 @@
+//! Time and randomness sources used by the WASI clock and random functions.
+//!
+//! Sources are part of the `ProcessConfig` and shared by all processes of a runtime. Tests can
+//! replace them with a `VirtualClock` and a `SeededRandom` to get reproducible results.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::{rngs::OsRng, rngs::SmallRng, RngCore, SeedableRng};
+use smol::channel::{bounded, Sender};
+use smol::future::{self, Boxed};
+use smol::Timer;
+
+pub trait Clock: Send + Sync {
+    /// Wall clock time in nanoseconds since the unix epoch.
+    fn realtime(&self) -> u64;
+
+    /// Nanoseconds since an arbitrary, fixed point in the past. Never goes backwards.
+    fn monotonic(&self) -> u64;
+
+    /// Resolution of both clocks in nanoseconds.
+    fn resolution(&self) -> u64;
+
+    /// Resolves once the monotonic clock reaches `deadline`. All timeouts of the runtime wait
+    /// through this, so that they follow the clock instead of the host's time.
+    fn sleep_until(&self, deadline: u64) -> Boxed<()>;
+}
+
+/// Uses the host's clocks.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn realtime(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+
+    fn monotonic(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn resolution(&self) -> u64 {
+        1
+    }
+
+    fn sleep_until(&self, deadline: u64) -> Boxed<()> {
+        let timer = Timer::at(self.start + Duration::from_nanos(deadline));
+        Box::pin(async move {
+            timer.await;
+        })
+    }
+}
+
+/// A clock that only moves when `advance` is called. Sleeping processes wake up once the clock is
+/// advanced past their deadline.
+pub struct VirtualClock {
+    realtime: AtomicU64,
+    monotonic: AtomicU64,
+    // Deadlines of sleepers, they are woken up by dropping the sender.
+    sleepers: Mutex<Vec<(u64, Sender<()>)>>,
+}
+
+impl VirtualClock {
+    /// Creates a clock frozen at `realtime` (time since the unix epoch). The monotonic clock starts at 0.
+    pub fn new(realtime: Duration) -> Self {
+        Self {
+            realtime: AtomicU64::new(realtime.as_nanos() as u64),
+            monotonic: AtomicU64::new(0),
+            sleepers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let nanos = duration.as_nanos() as u64;
+        self.realtime.fetch_add(nanos, Ordering::SeqCst);
+        let now = self.monotonic.fetch_add(nanos, Ordering::SeqCst) + nanos;
+        self.sleepers
+            .lock()
+            .unwrap()
+            .retain(|(deadline, _)| *deadline > now);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn realtime(&self) -> u64 {
+        self.realtime.load(Ordering::SeqCst)
+    }
+
+    fn monotonic(&self) -> u64 {
+        self.monotonic.load(Ordering::SeqCst)
+    }
+
+    fn resolution(&self) -> u64 {
+        1
+    }
+
+    fn sleep_until(&self, deadline: u64) -> Boxed<()> {
+        // Checked while holding the lock, so that a concurrent `advance` can't be missed.
+        let mut sleepers = self.sleepers.lock().unwrap();
+        if deadline <= self.monotonic() {
+            return Box::pin(future::ready(()));
+        }
+        let (sender, receiver) = bounded(1);
+        sleepers.push((deadline, sender));
+        Box::pin(async move {
+            let _ = receiver.recv().await;
+        })
+    }
+}
+
+pub trait RandomSource: Send + Sync {
+    fn fill(&self, buffer: &mut [u8]);
+}
+
+/// Uses the host's secure random number generator.
+#[derive(Clone, Copy, Default)]
+pub struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill(&self, buffer: &mut [u8]) {
+        OsRng.fill_bytes(buffer);
+    }
+}
+
+/// A deterministic, but not cryptographically secure, random number generator.
+pub struct SeededRandom(Mutex<SmallRng>);
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(SmallRng::seed_from_u64(seed)))
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn fill(&self, buffer: &mut [u8]) {
+        self.0.lock().unwrap().fill_bytes(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_sleep_waits_for_advance() {
+        let clock = VirtualClock::new(Duration::from_secs(0));
+        let mut sleep = clock.sleep_until(1_000);
+        let mut later = clock.sleep_until(2_000);
+        assert!(future::block_on(future::poll_once(&mut sleep)).is_none());
+
+        clock.advance(Duration::from_nanos(999));
+        assert!(future::block_on(future::poll_once(&mut sleep)).is_none());
+        clock.advance(Duration::from_nanos(1));
+        assert!(future::block_on(future::poll_once(&mut sleep)).is_some());
+        assert!(future::block_on(future::poll_once(&mut later)).is_none());
+
+        // Deadlines in the past resolve immediately.
+        assert!(future::block_on(future::poll_once(clock.sleep_until(500))).is_some());
+    }
+}
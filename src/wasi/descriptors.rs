@@ -0,0 +1,246 @@
+//! The WASI file descriptor table of a process.
+//!
+//! Stdio, files and sockets share one table, so guests can use the same fd based calls (e.g.
+//! `fd_read`) on all of them. Stdio always occupies fds 0-2, followed by the preopened host
+//! directories, the virtual directories and then the preopened sockets, each in the order they
+//! were configured. Preopened directories need to come first, because WASI libc stops scanning for
+//! them at the first fd that isn't one.
+
+use std::fs::File;
+
+use super::filesystem::{Directory, PreopenedDir};
+use super::stdio::OutputWriter;
+use super::types::*;
+use super::vfs::{VirtualDir, VirtualDirectory, VirtualFile};
+use crate::networking::{TcpListener, TcpStream};
+
+// Rights of files opened for reading and writing.
+const FILE_RIGHTS: u64 = WASI_RIGHT_FD_READ
+    | WASI_RIGHT_FD_WRITE
+    | WASI_RIGHT_FD_SEEK
+    | WASI_RIGHT_FD_TELL
+    | WASI_RIGHT_FD_FDSTAT_SET_FLAGS
+    | WASI_RIGHT_FD_FILESTAT_GET
+    | WASI_RIGHT_POLL_FD_READWRITE;
+
+const DIRECTORY_RIGHTS: u64 = WASI_RIGHT_PATH_OPEN
+    | WASI_RIGHT_PATH_CREATE_FILE
+    | WASI_RIGHT_PATH_FILESTAT_GET
+    | WASI_RIGHT_FD_READDIR
+    | WASI_RIGHT_FD_FDSTAT_SET_FLAGS
+    | WASI_RIGHT_FD_FILESTAT_GET;
+
+// Stdio doesn't have the seek and tell rights, so that WASI libc's `isatty` recognizes it.
+const STDIN_RIGHTS: u64 = WASI_RIGHT_FD_READ
+    | WASI_RIGHT_FD_FDSTAT_SET_FLAGS
+    | WASI_RIGHT_FD_FILESTAT_GET
+    | WASI_RIGHT_POLL_FD_READWRITE;
+
+const STDOUT_RIGHTS: u64 = WASI_RIGHT_FD_WRITE
+    | WASI_RIGHT_FD_FDSTAT_SET_FLAGS
+    | WASI_RIGHT_FD_FILESTAT_GET
+    | WASI_RIGHT_POLL_FD_READWRITE;
+
+const TCP_STREAM_RIGHTS: u64 = WASI_RIGHT_FD_READ
+    | WASI_RIGHT_FD_WRITE
+    | WASI_RIGHT_FD_FDSTAT_SET_FLAGS
+    | WASI_RIGHT_FD_FILESTAT_GET
+    | WASI_RIGHT_POLL_FD_READWRITE
+    | WASI_RIGHT_SOCK_SHUTDOWN;
+
+const TCP_LISTENER_RIGHTS: u64 = WASI_RIGHT_FD_FDSTAT_SET_FLAGS
+    | WASI_RIGHT_FD_FILESTAT_GET
+    | WASI_RIGHT_POLL_FD_READWRITE
+    | WASI_RIGHT_SOCK_ACCEPT;
+
+pub enum Descriptor {
+    Stdin,
+    Stdout(OutputWriter),
+    Stderr(OutputWriter),
+    File(File),
+    Directory(Directory),
+    VirtualFile(VirtualFile),
+    VirtualDirectory(VirtualDirectory),
+    TcpListener(TcpListener),
+    TcpStream(TcpStream),
+}
+
+impl Descriptor {
+    pub fn filetype(&self) -> u8 {
+        match self {
+            Descriptor::Stdin => WASI_FILETYPE_CHARACTER_DEVICE,
+            Descriptor::Stdout(output) | Descriptor::Stderr(output) => output.filetype(),
+            Descriptor::File(_) | Descriptor::VirtualFile(_) => WASI_FILETYPE_REGULAR_FILE,
+            Descriptor::Directory(_) | Descriptor::VirtualDirectory(_) => WASI_FILETYPE_DIRECTORY,
+            Descriptor::TcpListener(_) | Descriptor::TcpStream(_) => WASI_FILETYPE_SOCKET_STREAM,
+        }
+    }
+
+    // All rights the runtime supports for this type of descriptor.
+    fn rights(&self) -> u64 {
+        match self {
+            Descriptor::Stdin => STDIN_RIGHTS,
+            Descriptor::Stdout(_) | Descriptor::Stderr(_) => STDOUT_RIGHTS,
+            Descriptor::File(_) | Descriptor::VirtualFile(_) => FILE_RIGHTS,
+            Descriptor::Directory(_) | Descriptor::VirtualDirectory(_) => DIRECTORY_RIGHTS,
+            Descriptor::TcpListener(_) => TCP_LISTENER_RIGHTS,
+            Descriptor::TcpStream(_) => TCP_STREAM_RIGHTS,
+        }
+    }
+
+    // Rights of descriptors opened through this one.
+    fn rights_inheriting(&self) -> u64 {
+        match self {
+            Descriptor::Directory(_) | Descriptor::VirtualDirectory(_) => {
+                DIRECTORY_RIGHTS | FILE_RIGHTS
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns the path under which the directory was preopened, `None` for all other descriptors.
+    pub fn preopened_path(&self) -> Option<&str> {
+        match self {
+            Descriptor::Directory(directory) => directory.guest_path(),
+            Descriptor::VirtualDirectory(directory) => directory.guest_path(),
+            _ => None,
+        }
+    }
+}
+
+struct Entry {
+    descriptor: Descriptor,
+    rights: u64,
+    // WASI fd flags, e.g. `WASI_FDFLAG_APPEND`.
+    flags: u32,
+}
+
+pub struct FdTable {
+    fds: Vec<Option<Entry>>,
+}
+
+impl FdTable {
+    pub fn new(
+        stdout: OutputWriter,
+        stderr: OutputWriter,
+        preopened_dirs: &[PreopenedDir],
+        virtual_dirs: &[VirtualDir],
+        preopened_sockets: &[TcpListener],
+    ) -> Self {
+        let mut table = Self { fds: Vec::new() };
+        table.add(Descriptor::Stdin);
+        table.add(Descriptor::Stdout(stdout));
+        table.add(Descriptor::Stderr(stderr));
+        for dir in preopened_dirs {
+            table.add(Descriptor::Directory(Directory::preopened(dir)));
+        }
+        for dir in virtual_dirs {
+            table.add(Descriptor::VirtualDirectory(VirtualDirectory::preopened(
+                dir,
+            )));
+        }
+        for listener in preopened_sockets {
+            table.add(Descriptor::TcpListener(listener.clone()));
+        }
+        table
+    }
+
+    /// Adds the descriptor with all rights supported for its type and no flags.
+    pub fn add(&mut self, descriptor: Descriptor) -> u32 {
+        let rights = descriptor.rights();
+        self.add_with(descriptor, rights, 0)
+    }
+
+    /// Adds the descriptor under the lowest free fd, like POSIX does. `rights` are limited to the
+    /// rights supported for the descriptor type.
+    pub fn add_with(&mut self, descriptor: Descriptor, rights: u64, flags: u32) -> u32 {
+        let entry = Entry {
+            rights: rights & descriptor.rights(),
+            descriptor,
+            flags,
+        };
+        match self.fds.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.fds[fd] = Some(entry);
+                fd as u32
+            }
+            None => {
+                self.fds.push(Some(entry));
+                (self.fds.len() - 1) as u32
+            }
+        }
+    }
+
+    pub fn get(&self, fd: u32) -> Option<&Descriptor> {
+        self.entry(fd).map(|entry| &entry.descriptor)
+    }
+
+    pub fn get_mut(&mut self, fd: u32) -> Option<&mut Descriptor> {
+        self.fds
+            .get_mut(fd as usize)
+            .and_then(Option::as_mut)
+            .map(|entry| &mut entry.descriptor)
+    }
+
+    /// Returns the descriptor if it was opened with all of the `rights`, otherwise
+    /// `WASI_ENOTCAPABLE`. Rights are checked before the descriptor type.
+    pub fn get_with_rights(&self, fd: u32, rights: u64) -> Result<&Descriptor, u32> {
+        match self.entry(fd) {
+            Some(entry) if entry.rights & rights == rights => Ok(&entry.descriptor),
+            Some(_) => Err(WASI_ENOTCAPABLE),
+            None => Err(WASI_EBADF),
+        }
+    }
+
+    pub fn get_mut_with_rights(&mut self, fd: u32, rights: u64) -> Result<&mut Descriptor, u32> {
+        match self.fds.get_mut(fd as usize).and_then(Option::as_mut) {
+            Some(entry) if entry.rights & rights == rights => Ok(&mut entry.descriptor),
+            Some(_) => Err(WASI_ENOTCAPABLE),
+            None => Err(WASI_EBADF),
+        }
+    }
+
+    pub fn remove(&mut self, fd: u32) -> Option<Descriptor> {
+        self.fds
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .map(|entry| entry.descriptor)
+    }
+
+    /// Serializes the descriptor's type, flags and rights as a WASI `fdstat` struct.
+    pub fn fdstat(&self, fd: u32) -> Option<[u8; WASI_FDSTAT_SIZE]> {
+        let entry = self.entry(fd)?;
+        let mut fdstat = [0; WASI_FDSTAT_SIZE];
+        fdstat[0] = entry.descriptor.filetype();
+        fdstat[2..4].copy_from_slice(&(entry.flags as u16).to_le_bytes());
+        fdstat[8..16].copy_from_slice(&entry.rights.to_le_bytes());
+        fdstat[16..24].copy_from_slice(&entry.descriptor.rights_inheriting().to_le_bytes());
+        Some(fdstat)
+    }
+
+    /// Changes the fd flags. Only `WASI_FDFLAG_NONBLOCK` can be changed after a descriptor was
+    /// opened, all other flags need to stay the same.
+    pub fn set_flags(&mut self, fd: u32, flags: u32) -> Result<(), u32> {
+        let entry = match self.fds.get_mut(fd as usize).and_then(Option::as_mut) {
+            Some(entry) => entry,
+            None => return Err(WASI_EBADF),
+        };
+        if (entry.flags ^ flags) & !WASI_FDFLAG_NONBLOCK != 0 {
+            return Err(WASI_ENOTSUP);
+        }
+        entry.flags = flags;
+        Ok(())
+    }
+
+    /// Non-blocking descriptors return `WASI_EAGAIN` instead of suspending the process.
+    pub fn is_nonblocking(&self, fd: u32) -> bool {
+        match self.entry(fd) {
+            Some(entry) => entry.flags & WASI_FDFLAG_NONBLOCK != 0,
+            None => false,
+        }
+    }
+
+    fn entry(&self, fd: u32) -> Option<&Entry> {
+        self.fds.get(fd as usize).and_then(Option::as_ref)
+    }
+}
@@ -0,0 +1,617 @@
+//! Host directories exposed to guests through WASI preopens.
+//!
+//! Guests can only access paths inside of a preopened directory. Paths are resolved relative to
+//! a directory descriptor, absolute paths and paths escaping the preopened root (through `..` or
+//! symbolic links) are rejected with `WASI_ENOTCAPABLE`. Files are checked again after they are
+//! opened, in case a symbolic link was swapped in between resolving the path and opening it.
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Error};
+use smol::unblock;
+
+use super::types::*;
+use super::vfs::OpenOptions;
+
+/// A host directory that is made accessible to the guest under `guest_path`.
+///
+/// Parsed from strings of the form `host[::guest]`. If the guest path is omitted, the directory is
+/// visible under the same path as on the host.
+#[derive(Clone, Debug)]
+pub struct PreopenedDir {
+    // Canonical path, used to detect paths escaping the directory.
+    host_path: PathBuf,
+    guest_path: String,
+}
+
+impl PreopenedDir {
+    pub fn new(host_path: impl AsRef<Path>, guest_path: String) -> Result<Self, io::Error> {
+        let host_path = host_path.as_ref().canonicalize()?;
+        if !host_path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a directory", host_path.display()),
+            ));
+        }
+        Ok(Self {
+            host_path,
+            guest_path,
+        })
+    }
+
+    pub fn host_path(&self) -> &Path {
+        &self.host_path
+    }
+
+    pub fn guest_path(&self) -> &str {
+        &self.guest_path
+    }
+}
+
+impl FromStr for PreopenedDir {
+    type Err = Error;
+
+    fn from_str(dir: &str) -> Result<Self, Self::Err> {
+        let (host, guest) = split_mapping(dir);
+        Self::new(host, guest.to_owned())
+            .map_err(|err| anyhow!("Can't preopen directory `{}`: {}", host, err))
+    }
+}
+
+/// Splits a directory mapping `source[::guest]` into the source and the guest path, which is the
+/// same as the source if it's omitted. A single colon doesn't separate them, so that paths like
+/// `C:\data` or `/tmp/a:b` can be used as they are.
+pub(super) fn split_mapping(mapping: &str) -> (&str, &str) {
+    match mapping.find("::") {
+        Some(separator) => (&mapping[..separator], &mapping[separator + 2..]),
+        None => (mapping, mapping),
+    }
+}
+
+/// An open directory inside of a preopened directory.
+#[derive(Clone, Debug)]
+pub struct Directory {
+    root: PathBuf,
+    path: PathBuf,
+    // Only set for preopened directories.
+    guest_path: Option<String>,
+}
+
+impl Directory {
+    pub fn preopened(dir: &PreopenedDir) -> Self {
+        Self {
+            root: dir.host_path.clone(),
+            path: dir.host_path.clone(),
+            guest_path: Some(dir.guest_path.clone()),
+        }
+    }
+
+    /// Opens a subdirectory that was previously resolved with `resolve`.
+    pub fn open(&self, host_path: PathBuf) -> Self {
+        Self {
+            root: self.root.clone(),
+            path: host_path,
+            guest_path: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path under which the directory was preopened, `None` if it wasn't.
+    pub fn guest_path(&self) -> Option<&str> {
+        self.guest_path.as_deref()
+    }
+
+    /// Resolves the guest `path` relative to this directory to a host path. Fails with
+    /// `WASI_ENOTCAPABLE` if the path escapes the preopened root. Unless `follow` is set, a
+    /// symbolic link in the last component is not followed and may point anywhere.
+    pub fn resolve(&self, path: &str, follow: bool) -> Result<PathBuf, u32> {
+        // `self.path` is always inside of `self.root`.
+        let mut relative = self.path.strip_prefix(&self.root).unwrap().to_path_buf();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(WASI_ENOTCAPABLE);
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(WASI_ENOTCAPABLE),
+            }
+        }
+        let resolved = self.root.join(relative);
+        let checked = match resolved.parent() {
+            Some(parent) if !follow && resolved != self.root => parent,
+            _ => &resolved,
+        };
+
+        // Symbolic links could still point outside of the root.
+        let canonical = match checked.canonicalize() {
+            Ok(canonical) => canonical,
+            // The path doesn't exist yet (e.g. a file that is about to be created).
+            Err(_) if checked.symlink_metadata().is_err() => match checked.parent() {
+                Some(parent) => parent
+                    .canonicalize()
+                    .map_err(|err| io_error_to_errno(&err))?,
+                None => return Err(WASI_ENOTCAPABLE),
+            },
+            // Dangling symbolic link
+            Err(_) => return Err(WASI_ENOTCAPABLE),
+        };
+        if canonical.starts_with(&self.root) {
+            Ok(resolved)
+        } else {
+            Err(WASI_ENOTCAPABLE)
+        }
+    }
+
+    /// Returns the metadata of the file at the guest `path`, see `resolve`.
+    pub async fn metadata(&self, path: &str, follow: bool) -> Result<Metadata, u32> {
+        let (directory, path) = (self.clone(), path.to_owned());
+        unblock(move || directory.metadata_blocking(&path, follow)).await
+    }
+
+    /// Opens the file at the guest `path`. Fails with `WASI_ELOOP` if the last component is a
+    /// symbolic link and `follow` isn't set. Truncation is only done after the opened file was
+    /// verified to be the one inside of the root.
+    pub async fn open_file(
+        &self,
+        path: &str,
+        options: OpenOptions,
+        follow: bool,
+    ) -> Result<File, u32> {
+        let (directory, path) = (self.clone(), path.to_owned());
+        unblock(move || directory.open_file_blocking(&path, options, follow)).await
+    }
+
+    /// Opens the subdirectory at the guest `path`, see `resolve`.
+    pub async fn open_dir(&self, path: &str, follow: bool) -> Result<Self, u32> {
+        let (directory, path) = (self.clone(), path.to_owned());
+        unblock(move || {
+            let host_path = directory.resolve(&path, follow)?;
+            Ok(directory.open(host_path))
+        })
+        .await
+    }
+
+    /// Writes the directory entries starting at `cookie` into `buffer`, see `write_dirents`.
+    pub async fn read_entries(&self, buffer: &mut [u8], cookie: u64) -> Result<usize, io::Error> {
+        let directory = self.clone();
+        let entries = unblock(move || directory.entries()).await?;
+        let entries = entries
+            .iter()
+            .map(|(name, inode, filetype)| (name.as_str(), *inode, *filetype));
+        Ok(write_dirents(buffer, cookie, entries))
+    }
+
+    // The file system calls block, the functions above run them on the blocking thread pool.
+
+    fn metadata_blocking(&self, path: &str, follow: bool) -> Result<Metadata, u32> {
+        let host_path = self.resolve(path, follow)?;
+        let metadata = if follow {
+            fs::metadata(host_path)
+        } else {
+            fs::symlink_metadata(host_path)
+        };
+        metadata.map_err(|err| io_error_to_errno(&err))
+    }
+
+    fn open_file_blocking(
+        &self,
+        path: &str,
+        options: OpenOptions,
+        follow: bool,
+    ) -> Result<File, u32> {
+        let host_path = self.resolve(path, follow)?;
+        if !follow && is_symlink(&host_path) {
+            return Err(WASI_ELOOP);
+        }
+        let file = fs::OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .create(options.create)
+            .create_new(options.create_new)
+            .open(&host_path)
+            .map_err(|err| io_error_to_errno(&err))?;
+
+        // A component could have been replaced with a symbolic link after the path was resolved,
+        // the path needs to still lead to the opened file.
+        let opened = file.metadata().map_err(|err| io_error_to_errno(&err))?;
+        let expected = self.metadata_blocking(path, follow)?;
+        if (device(&opened), inode(&opened)) != (device(&expected), inode(&expected)) {
+            return Err(WASI_ENOTCAPABLE);
+        }
+        if options.truncate {
+            file.set_len(0).map_err(|err| io_error_to_errno(&err))?;
+        }
+        Ok(file)
+    }
+
+    // Returns the `(name, inode, filetype)` of all entries, in the order used for cookies.
+    fn entries(&self) -> Result<Vec<(String, u64, u8)>, io::Error> {
+        // The parent of the root is not visible to the guest, `..` refers to the root itself.
+        let parent = match self.path.parent() {
+            Some(parent) if self.path != self.root => parent,
+            _ => &self.path,
+        };
+        let mut entries = vec![
+            (".".to_owned(), fs::metadata(&self.path)?),
+            ("..".to_owned(), fs::metadata(parent)?),
+        ];
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            names.push((entry.file_name(), entry.path()));
+        }
+        // Cookies are indexes into the entries, so the order needs to be stable between calls.
+        names.sort();
+        for (name, path) in names {
+            entries.push((
+                name.to_string_lossy().into_owned(),
+                fs::symlink_metadata(path)?,
+            ));
+        }
+
+        Ok(entries
+            .into_iter()
+            .map(|(name, metadata)| (name, inode(&metadata), filetype(&metadata)))
+            .collect())
+    }
+}
+
+/// Writes to a host file on the blocking thread pool, so that slow disks don't stall the executor.
+pub async fn write_file(file: &File, bufs: &[IoSlice<'_>]) -> Result<usize, io::Error> {
+    // The clone shares the file offset.
+    let mut file = file.try_clone()?;
+    let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+    unblock(move || file.write(&data)).await
+}
+
+/// Reads from a host file on the blocking thread pool, see `write_file`.
+pub async fn read_file(file: &File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, io::Error> {
+    let mut file = file.try_clone()?;
+    let len = bufs.iter().map(|buf| buf.len()).sum();
+    let data = unblock(move || {
+        let mut data = vec![0; len];
+        let read = file.read(&mut data)?;
+        data.truncate(read);
+        Ok::<_, io::Error>(data)
+    })
+    .await?;
+
+    let mut copied = 0;
+    for buf in bufs.iter_mut() {
+        let len = buf.len().min(data.len() - copied);
+        buf[..len].copy_from_slice(&data[copied..copied + len]);
+        copied += len;
+    }
+    Ok(copied)
+}
+
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Writes `(name, inode, filetype)` entries starting at `cookie` into `buffer` as WASI `dirent`
+/// structs followed by the entry name. Returns the number of bytes used. If the buffer is too
+/// small the last entry is truncated and the whole buffer is used, as expected by WASI.
+pub(super) fn write_dirents<'a>(
+    buffer: &mut [u8],
+    cookie: u64,
+    entries: impl Iterator<Item = (&'a str, u64, u8)>,
+) -> usize {
+    let mut used = 0;
+    for (index, (name, inode, filetype)) in entries.enumerate().skip(cookie as usize) {
+        let mut dirent = [0; WASI_DIRENT_SIZE];
+        dirent[0..8].copy_from_slice(&(index as u64 + 1).to_le_bytes());
+        dirent[8..16].copy_from_slice(&inode.to_le_bytes());
+        dirent[16..20].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        dirent[20] = filetype;
+
+        for part in [&dirent[..], name.as_bytes()].iter() {
+            let available = buffer.len() - used;
+            let len = part.len().min(available);
+            buffer[used..used + len].copy_from_slice(&part[..len]);
+            used += len;
+        }
+        if used == buffer.len() {
+            break;
+        }
+    }
+    used
+}
+
+/// Attributes of a file, serialized into the `filestat` struct of both WASI versions.
+#[derive(Default)]
+pub struct Filestat {
+    pub(super) device: u64,
+    pub(super) inode: u64,
+    pub(super) filetype: u8,
+    pub(super) link_count: u64,
+    pub(super) size: u64,
+    pub(super) accessed: u64,
+    pub(super) modified: u64,
+    pub(super) created: u64,
+}
+
+impl Filestat {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        Self {
+            device: device(metadata),
+            inode: inode(metadata),
+            filetype: filetype(metadata),
+            link_count: link_count(metadata),
+            size: metadata.len(),
+            accessed: timestamp(metadata.accessed()),
+            modified: timestamp(metadata.modified()),
+            created: timestamp(metadata.created()),
+        }
+    }
+
+    /// Descriptors that aren't backed by a host file (e.g. stdio and sockets) only have a type.
+    pub fn of_type(filetype: u8) -> Self {
+        Self {
+            filetype,
+            link_count: 1,
+            ..Self::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; WASI_FILESTAT_SIZE] {
+        let mut filestat = [0; WASI_FILESTAT_SIZE];
+        filestat[0..8].copy_from_slice(&self.device.to_le_bytes());
+        filestat[8..16].copy_from_slice(&self.inode.to_le_bytes());
+        filestat[16] = self.filetype;
+        filestat[24..32].copy_from_slice(&self.link_count.to_le_bytes());
+        filestat[32..40].copy_from_slice(&self.size.to_le_bytes());
+        filestat[40..48].copy_from_slice(&self.accessed.to_le_bytes());
+        filestat[48..56].copy_from_slice(&self.modified.to_le_bytes());
+        filestat[56..64].copy_from_slice(&self.created.to_le_bytes());
+        filestat
+    }
+
+    /// Serializes as a preview0 `filestat` struct, which has a 32 bit link count.
+    pub fn to_unstable_bytes(&self) -> [u8; WASI_UNSTABLE_FILESTAT_SIZE] {
+        let mut filestat = [0; WASI_UNSTABLE_FILESTAT_SIZE];
+        filestat[0..8].copy_from_slice(&self.device.to_le_bytes());
+        filestat[8..16].copy_from_slice(&self.inode.to_le_bytes());
+        filestat[16] = self.filetype;
+        filestat[20..24].copy_from_slice(&(self.link_count as u32).to_le_bytes());
+        filestat[24..32].copy_from_slice(&self.size.to_le_bytes());
+        filestat[32..40].copy_from_slice(&self.accessed.to_le_bytes());
+        filestat[40..48].copy_from_slice(&self.modified.to_le_bytes());
+        filestat[48..56].copy_from_slice(&self.created.to_le_bytes());
+        filestat
+    }
+}
+
+fn filetype(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        return WASI_FILETYPE_DIRECTORY;
+    }
+    if file_type.is_file() {
+        return WASI_FILETYPE_REGULAR_FILE;
+    }
+    if file_type.is_symlink() {
+        return WASI_FILETYPE_SYMBOLIC_LINK;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_block_device() {
+            return WASI_FILETYPE_BLOCK_DEVICE;
+        }
+        if file_type.is_char_device() {
+            return WASI_FILETYPE_CHARACTER_DEVICE;
+        }
+        if file_type.is_socket() {
+            return WASI_FILETYPE_SOCKET_STREAM;
+        }
+    }
+    WASI_FILETYPE_UNKNOWN
+}
+
+// Nanoseconds since the unix epoch, 0 if the platform doesn't provide the time.
+fn timestamp(time: io::Result<SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn device(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::dev(metadata)
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(metadata)
+}
+
+#[cfg(not(unix))]
+fn device(_metadata: &Metadata) -> u64 {
+    0
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &Metadata) -> u64 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use smol::future;
+
+    use super::*;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    // A fresh host directory containing `outside/secret` and `root/file`, `root` is preopened.
+    fn preopened() -> (PathBuf, Directory) {
+        let base = std::env::temp_dir().join(format!(
+            "lunatic-filesystem-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::create_dir_all(base.join("root/dir")).unwrap();
+        fs::write(base.join("outside/secret"), "secret").unwrap();
+        fs::write(base.join("root/file"), "file").unwrap();
+        let dir = PreopenedDir::new(base.join("root"), "/data".to_owned()).unwrap();
+        (base, Directory::preopened(&dir))
+    }
+
+    fn read_only() -> OpenOptions {
+        OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        }
+    }
+
+    #[test]
+    fn directory_mappings() {
+        assert_eq!(split_mapping("/data"), ("/data", "/data"));
+        assert_eq!(split_mapping("/tmp/data::/data"), ("/tmp/data", "/data"));
+        assert_eq!(split_mapping("C:\\data"), ("C:\\data", "C:\\data"));
+        assert_eq!(split_mapping("C:\\data::/data"), ("C:\\data", "/data"));
+        assert_eq!(split_mapping("/tmp/a:b"), ("/tmp/a:b", "/tmp/a:b"));
+        assert_eq!(split_mapping("::/data"), ("", "/data"));
+    }
+
+    #[test]
+    fn paths_escaping_the_root() {
+        let (base, root) = preopened();
+        assert_eq!(
+            root.resolve("../outside/secret", true),
+            Err(WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            root.resolve("dir/../../outside", true),
+            Err(WASI_ENOTCAPABLE)
+        );
+        let absolute = base.join("outside/secret");
+        assert_eq!(
+            root.resolve(absolute.to_str().unwrap(), true),
+            Err(WASI_ENOTCAPABLE)
+        );
+        assert_eq!(root.resolve("/file", true), Err(WASI_ENOTCAPABLE));
+
+        // `..` is fine as long as it stays inside.
+        let file = root.resolve("dir/../file", true).unwrap();
+        assert_eq!(fs::read_to_string(file).unwrap(), "file");
+        let dir = root.open(root.resolve("dir", true).unwrap());
+        assert_eq!(dir.resolve("../../outside", true), Err(WASI_ENOTCAPABLE));
+        assert!(future::block_on(dir.open_file("../file", read_only(), true)).is_ok());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_escaping_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let (base, root) = preopened();
+        symlink(base.join("outside"), base.join("root/escape")).unwrap();
+        symlink(base.join("outside/secret"), base.join("root/secret")).unwrap();
+        symlink("file", base.join("root/inside")).unwrap();
+
+        assert_eq!(root.resolve("escape/secret", true), Err(WASI_ENOTCAPABLE));
+        assert_eq!(root.resolve("escape/secret", false), Err(WASI_ENOTCAPABLE));
+        assert_eq!(
+            future::block_on(root.open_file("secret", read_only(), true)).err(),
+            Some(WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            future::block_on(root.open_file("secret", read_only(), false)).err(),
+            Some(WASI_ELOOP)
+        );
+        // The link itself is inside of the root and can be inspected.
+        let link = future::block_on(root.metadata("secret", false)).unwrap();
+        assert!(link.file_type().is_symlink());
+        assert_eq!(
+            future::block_on(root.metadata("secret", true)).err(),
+            Some(WASI_ENOTCAPABLE)
+        );
+
+        assert!(future::block_on(root.open_file("inside", read_only(), true)).is_ok());
+        assert_eq!(
+            future::block_on(root.open_file("inside", read_only(), false)).err(),
+            Some(WASI_ELOOP)
+        );
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn truncate_after_opening() {
+        let (base, root) = preopened();
+        let options = OpenOptions {
+            write: true,
+            truncate: true,
+            ..OpenOptions::default()
+        };
+        future::block_on(root.open_file("file", options, true)).unwrap();
+        assert_eq!(fs::read_to_string(base.join("root/file")).unwrap(), "");
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn parent_entry_of_the_root() {
+        let (base, root) = preopened();
+        let mut buffer = [0; 1024];
+        let used = future::block_on(root.read_entries(&mut buffer, 0)).unwrap();
+        // `.` and `..` come first, followed by `dir` and `file`.
+        let entries: Vec<(String, u64)> = dirents(&buffer[..used]);
+        assert_eq!(entries[0].0, ".");
+        assert_eq!(entries[1], ("..".to_owned(), entries[0].1));
+        assert_eq!(entries[2].0, "dir");
+        assert_eq!(entries[3].0, "file");
+
+        let dir = root.open(root.resolve("dir", true).unwrap());
+        let used = future::block_on(dir.read_entries(&mut buffer, 0)).unwrap();
+        let entries = dirents(&buffer[..used]);
+        assert_eq!(
+            entries[1].1,
+            inode(&fs::metadata(base.join("root")).unwrap())
+        );
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    // Parses `(name, inode)` pairs back from `dirent`s.
+    fn dirents(mut buffer: &[u8]) -> Vec<(String, u64)> {
+        let mut entries = Vec::new();
+        while !buffer.is_empty() {
+            let inode = u64::from_le_bytes(buffer[8..16].try_into().unwrap());
+            let len = u32::from_le_bytes(buffer[16..20].try_into().unwrap()) as usize;
+            let name = &buffer[WASI_DIRENT_SIZE..WASI_DIRENT_SIZE + len];
+            entries.push((String::from_utf8(name.to_vec()).unwrap(), inode));
+            buffer = &buffer[WASI_DIRENT_SIZE + len..];
+        }
+        entries
+    }
+}
@@ -1,2 +1,9 @@
 pub mod api;
+pub mod clock;
+pub mod descriptors;
+pub mod filesystem;
+pub mod poll;
+pub mod stdio;
 pub mod types;
+pub mod unstable;
+pub mod vfs;
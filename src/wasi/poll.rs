@@ -0,0 +1,132 @@
+//! Subscriptions and events of `poll_oneoff`, as laid out in guest memory.
+
+use std::convert::TryInto;
+use std::task::Poll;
+
+use smol::future::{self, Boxed};
+
+use super::types::*;
+
+pub enum SubscriptionKind {
+    /// `timeout` is relative to the current time, unless `absolute` is set.
+    Clock {
+        id: u32,
+        timeout: u64,
+        absolute: bool,
+    },
+    FdRead(u32),
+    FdWrite(u32),
+}
+
+pub struct Subscription {
+    pub userdata: u64,
+    pub kind: SubscriptionKind,
+}
+
+impl Subscription {
+    /// Decodes a `subscription` struct, returns `None` if the event type is unknown.
+    pub fn parse(bytes: &[u8; WASI_SUBSCRIPTION_SIZE]) -> Option<Self> {
+        Self::parse_with_clock_at(bytes, 16)
+    }
+
+    /// Decodes a preview0 `subscription` struct. Its clock subscription has an additional
+    /// `identifier` field in front, all other fields are the same.
+    pub fn parse_unstable(bytes: &[u8; WASI_UNSTABLE_SUBSCRIPTION_SIZE]) -> Option<Self> {
+        Self::parse_with_clock_at(bytes, 24)
+    }
+
+    fn parse_with_clock_at(bytes: &[u8], clock: usize) -> Option<Self> {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let kind = match bytes[8] {
+            WASI_EVENTTYPE_CLOCK => {
+                let flags = u16::from_le_bytes([bytes[clock + 24], bytes[clock + 25]]);
+                SubscriptionKind::Clock {
+                    id: u32_at(clock),
+                    timeout: u64_at(clock + 8),
+                    absolute: flags & WASI_SUBSCRIPTION_CLOCK_ABSTIME != 0,
+                }
+            }
+            WASI_EVENTTYPE_FD_READ => SubscriptionKind::FdRead(u32_at(16)),
+            WASI_EVENTTYPE_FD_WRITE => SubscriptionKind::FdWrite(u32_at(16)),
+            _ => return None,
+        };
+        Some(Self {
+            userdata: u64_at(0),
+            kind,
+        })
+    }
+
+    pub fn event_type(&self) -> u8 {
+        match self.kind {
+            SubscriptionKind::Clock { .. } => WASI_EVENTTYPE_CLOCK,
+            SubscriptionKind::FdRead(_) => WASI_EVENTTYPE_FD_READ,
+            SubscriptionKind::FdWrite(_) => WASI_EVENTTYPE_FD_WRITE,
+        }
+    }
+}
+
+pub struct Event {
+    pub userdata: u64,
+    pub error: u32,
+    pub event_type: u8,
+    /// Bytes available for reading or writing, only used by fd events.
+    pub nbytes: u64,
+    /// Set if the peer closed the connection, only used by fd events.
+    pub hangup: bool,
+}
+
+impl Event {
+    pub fn ready(subscription: &Subscription, nbytes: u64, hangup: bool) -> Self {
+        Self {
+            userdata: subscription.userdata,
+            error: WASI_ESUCCESS,
+            event_type: subscription.event_type(),
+            nbytes,
+            hangup,
+        }
+    }
+
+    pub fn error(subscription: &Subscription, error: u32) -> Self {
+        Self {
+            userdata: subscription.userdata,
+            error,
+            event_type: subscription.event_type(),
+            nbytes: 0,
+            hangup: false,
+        }
+    }
+
+    /// Encodes the event as an `event` struct.
+    pub fn to_bytes(&self) -> [u8; WASI_EVENT_SIZE] {
+        let mut bytes = [0; WASI_EVENT_SIZE];
+        bytes[0..8].copy_from_slice(&self.userdata.to_le_bytes());
+        bytes[8..10].copy_from_slice(&(self.error as u16).to_le_bytes());
+        bytes[10] = self.event_type;
+        if self.event_type != WASI_EVENTTYPE_CLOCK {
+            let flags = if self.hangup {
+                WASI_EVENT_FD_READWRITE_HANGUP
+            } else {
+                0
+            };
+            bytes[16..24].copy_from_slice(&self.nbytes.to_le_bytes());
+            bytes[24..26].copy_from_slice(&flags.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Resolves to the output of the first future that completes, the others are dropped.
+pub async fn first_ready<T>(mut futures: Vec<Boxed<T>>) -> T {
+    future::poll_fn(|cx| {
+        for future in futures.iter_mut() {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready(output);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
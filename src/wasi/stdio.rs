@@ -0,0 +1,404 @@
+//! Stdio of processes.
+//!
+//! By default processes write directly to the host's streams. Because thousands of processes can
+//! run at the same time, their output can instead be prefixed with the process id, redirected to a
+//! file or channel, or captured in memory.
+//!
+//! All processes share the host's stdin. Reading from it happens on a separate thread, so that a
+//! process waiting for input doesn't block the executor thread it's running on. Writing to files
+//! happens on the blocking thread pool for the same reason.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Poll, Waker};
+use std::thread;
+
+use anyhow::{anyhow, Error};
+use lazy_static::lazy_static;
+use smol::{future, unblock};
+
+use super::types::*;
+use crate::channel::Channel;
+
+// Longer incomplete lines of `Output::Prefixed` are written out, so that the buffer doesn't grow
+// without bound.
+const MAX_PREFIXED_LINE: usize = 8 * 1024;
+
+lazy_static! {
+    static ref STDIN: Arc<SharedReader> = SharedReader::spawn(io::stdin());
+}
+
+/// Reads from the host's stdin, suspending the calling task until input is available.
+pub async fn read_stdin(bufs: &mut [IoSliceMut<'_>]) -> Result<usize, io::Error> {
+    STDIN.read(bufs).await
+}
+
+/// Waits until stdin has input and returns the number of bytes that can be read without waiting.
+/// Returns 0 once stdin is closed.
+pub async fn stdin_readable() -> Result<usize, io::Error> {
+    STDIN.readable().await
+}
+
+// A blocking reader shared between tasks. A thread reads the next chunk once the previous one
+// was consumed and someone waits for input. No lock is held while waiting, so that non-blocking
+// reads can fail right away while other tasks wait.
+struct SharedReader {
+    state: Mutex<ReaderState>,
+    // Signals the reading thread that more input is wanted.
+    wanted: Condvar,
+}
+
+#[derive(Default)]
+struct ReaderState {
+    buffer: Vec<u8>,
+    // Set once the end of the input is reached, reads return 0 afterwards.
+    closed: bool,
+    // Returned by all reads once the buffered input was consumed.
+    error: Option<io::ErrorKind>,
+    wanted: bool,
+    waiting: Vec<Waker>,
+}
+
+impl SharedReader {
+    fn spawn(mut reader: impl Read + Send + 'static) -> Arc<Self> {
+        let shared = Arc::new(Self {
+            state: Mutex::new(ReaderState::default()),
+            wanted: Condvar::new(),
+        });
+        let inner = shared.clone();
+        thread::spawn(move || loop {
+            let mut state = inner.state.lock().unwrap();
+            while !state.wanted {
+                state = inner.wanted.wait(state).unwrap();
+            }
+            drop(state);
+
+            let mut chunk = [0; 4096];
+            let result = reader.read(&mut chunk);
+            let mut state = inner.state.lock().unwrap();
+            match result {
+                Ok(0) => state.closed = true,
+                Ok(read) => state.buffer.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => state.error = Some(err.kind()),
+            }
+            state.wanted = false;
+            for waker in state.waiting.drain(..) {
+                waker.wake();
+            }
+            if state.closed || state.error.is_some() {
+                return;
+            }
+        });
+        shared
+    }
+
+    // Waits until there is buffered input or the input is closed and calls `f` with the buffer.
+    async fn with_input<T>(&self, mut f: impl FnMut(&mut Vec<u8>) -> T) -> io::Result<T> {
+        future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if !state.buffer.is_empty() || state.closed {
+                return Poll::Ready(Ok(f(&mut state.buffer)));
+            }
+            if let Some(kind) = state.error {
+                return Poll::Ready(Err(kind.into()));
+            }
+            if !state
+                .waiting
+                .iter()
+                .any(|waker| waker.will_wake(cx.waker()))
+            {
+                state.waiting.push(cx.waker().clone());
+            }
+            state.wanted = true;
+            self.wanted.notify_one();
+            Poll::Pending
+        })
+        .await
+    }
+
+    async fn read(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.with_input(|buffer| {
+            let mut read = 0;
+            for buf in bufs.iter_mut() {
+                let len = buf.len().min(buffer.len() - read);
+                buf[..len].copy_from_slice(&buffer[read..read + len]);
+                read += len;
+            }
+            buffer.drain(..read);
+            read
+        })
+        .await
+    }
+
+    async fn readable(&self) -> io::Result<usize> {
+        self.with_input(|buffer| buffer.len()).await
+    }
+}
+
+/// Where the output of a stream goes. Processes spawned from the guest inherit the outputs of
+/// their parent, so all of them write to the same destination.
+#[derive(Clone)]
+pub enum Output {
+    /// Write directly to the host's stream.
+    Inherit,
+    /// Write to the host's stream, prefixing each line with `[pid] `. Only complete lines are
+    /// written, so lines from different processes don't interleave. Lines longer than 8 KiB are
+    /// split.
+    Prefixed,
+    /// Append to a file.
+    File(Arc<Mutex<File>>),
+    /// Collect the output in memory, mostly useful for tests.
+    Buffer(OutputBuffer),
+    /// Send each write as a message to the channel.
+    Channel(Channel),
+    /// Discard the output.
+    Null,
+}
+
+impl Output {
+    /// Opens `path` for appending, creating the file if it doesn't exist.
+    pub fn file(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Output::File(Arc::new(Mutex::new(file))))
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Inherit
+    }
+}
+
+/// Parsed from `inherit`, `prefix`, `null` or the path of a file.
+impl FromStr for Output {
+    type Err = Error;
+
+    fn from_str(output: &str) -> Result<Self, Self::Err> {
+        match output {
+            "inherit" => Ok(Output::Inherit),
+            "prefix" => Ok(Output::Prefixed),
+            "null" => Ok(Output::Null),
+            path => Output::file(path).map_err(|err| anyhow!("Can't open `{}`: {}", path, err)),
+        }
+    }
+}
+
+/// In-memory output, shared between all clones.
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum HostStream {
+    Stdout,
+    Stderr,
+}
+
+/// The writing end of an `Output` belonging to one process.
+pub struct OutputWriter {
+    output: Output,
+    stream: HostStream,
+    process_id: u64,
+    // Incomplete last line, only used for `Output::Prefixed`.
+    line: Vec<u8>,
+}
+
+impl OutputWriter {
+    pub fn new(output: Output, stream: HostStream, process_id: u64) -> Self {
+        Self {
+            output,
+            stream,
+            process_id,
+            line: Vec::new(),
+        }
+    }
+
+    /// The WASI file type the guest sees. Host streams are reported as terminals.
+    pub fn filetype(&self) -> u8 {
+        match self.output {
+            Output::Inherit | Output::Prefixed | Output::Null => WASI_FILETYPE_CHARACTER_DEVICE,
+            Output::File(_) => WASI_FILETYPE_REGULAR_FILE,
+            Output::Buffer(_) | Output::Channel(_) => WASI_FILETYPE_UNKNOWN,
+        }
+    }
+
+    /// Writes all buffers and returns the number of bytes written.
+    pub async fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, io::Error> {
+        match &self.output {
+            Output::Inherit => {
+                let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+                let written = data.len();
+                let stream = self.stream;
+                unblock(move || stream.write_all(&data)).await?;
+                Ok(written)
+            }
+            Output::Prefixed => {
+                let mut written = 0;
+                for buf in bufs {
+                    self.line.extend_from_slice(buf);
+                    written += buf.len();
+                }
+                let lines = complete_lines(&mut self.line);
+                if !lines.is_empty() {
+                    let prefixed = self.prefixed(&lines);
+                    let stream = self.stream;
+                    unblock(move || stream.write_all(&prefixed)).await?;
+                }
+                Ok(written)
+            }
+            Output::File(file) => {
+                let file = file.clone();
+                let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+                let written = data.len();
+                unblock(move || file.lock().unwrap().write_all(&data)).await?;
+                Ok(written)
+            }
+            Output::Buffer(buffer) => {
+                let mut buffer = buffer.0.lock().unwrap();
+                let mut written = 0;
+                for buf in bufs {
+                    buffer.extend_from_slice(buf);
+                    written += buf.len();
+                }
+                Ok(written)
+            }
+            Output::Channel(channel) => {
+                let message = bufs.iter().fold(Vec::new(), |mut message, buf| {
+                    message.extend_from_slice(buf);
+                    message
+                });
+                channel.send(&message).await;
+                Ok(message.len())
+            }
+            Output::Null => Ok(bufs.iter().map(|buf| buf.len()).sum()),
+        }
+    }
+
+    // Prefixes each of the `lines` with the process id.
+    fn prefixed(&self, lines: &[u8]) -> Vec<u8> {
+        let mut prefixed = Vec::with_capacity(lines.len());
+        for line in lines.split_inclusive(|&byte| byte == b'\n') {
+            prefixed.extend_from_slice(format!("[{}] ", self.process_id).as_bytes());
+            prefixed.extend_from_slice(line);
+        }
+        prefixed
+    }
+}
+
+impl HostStream {
+    // Holds the lock of the host stream, so that other processes can't write in between. Blocks
+    // if the terminal or pipe is full, call it on the blocking thread pool.
+    fn write_all(self, data: &[u8]) -> Result<(), io::Error> {
+        match self {
+            HostStream::Stdout => io::stdout().lock().write_all(data),
+            HostStream::Stderr => io::stderr().lock().write_all(data),
+        }
+    }
+}
+
+// Removes all complete lines from `line` and returns them. Lines reaching `MAX_PREFIXED_LINE`
+// bytes without a newline are treated as complete.
+fn complete_lines(line: &mut Vec<u8>) -> Vec<u8> {
+    let mut lines = match line.iter().rposition(|&byte| byte == b'\n') {
+        Some(end) => line.drain(..=end).collect(),
+        None => Vec::new(),
+    };
+    while line.len() >= MAX_PREFIXED_LINE {
+        lines.extend(line.drain(..MAX_PREFIXED_LINE));
+        lines.push(b'\n');
+    }
+    lines
+}
+
+impl Drop for OutputWriter {
+    // Output that doesn't end with a newline is written once the process finishes.
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let mut line = std::mem::take(&mut self.line);
+            line.push(b'\n');
+            // Can't wait for the blocking thread pool here.
+            let _ = self.stream.write_all(&self.prefixed(&line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waiting_for_input_doesnt_block_readers() {
+        let (sender, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+        let input = SharedReader::spawn(ChannelReader(receiver));
+        let mut readable = Box::pin(input.readable());
+        assert!(future::block_on(future::poll_once(&mut readable)).is_none());
+
+        // A second reader isn't locked out while the first one waits.
+        let mut buf = [0; 4];
+        let read = future::block_on(future::poll_once(
+            input.read(&mut [IoSliceMut::new(&mut buf)]),
+        ));
+        assert!(read.is_none());
+
+        sender.send(b"hello".to_vec()).unwrap();
+        assert_eq!(future::block_on(readable).unwrap(), 5);
+        let read = future::block_on(input.read(&mut [IoSliceMut::new(&mut buf)]));
+        assert_eq!(read.unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+        assert_eq!(future::block_on(input.readable()).unwrap(), 1);
+
+        drop(sender);
+        let read = future::block_on(input.read(&mut [IoSliceMut::new(&mut buf)]));
+        assert_eq!(read.unwrap(), 1);
+        let read = future::block_on(input.read(&mut [IoSliceMut::new(&mut buf)]));
+        assert_eq!(read.unwrap(), 0);
+    }
+
+    // Each message is the result of one `read` call, a closed channel is the end of the input.
+    struct ChannelReader(std::sync::mpsc::Receiver<Vec<u8>>);
+
+    impl Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.recv() {
+                Ok(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut line = b"first\nsecond\nincomplete".to_vec();
+        assert_eq!(complete_lines(&mut line), b"first\nsecond\n");
+        assert_eq!(line, b"incomplete");
+        assert!(complete_lines(&mut line).is_empty());
+
+        let mut line = vec![b'a'; 2 * MAX_PREFIXED_LINE + 1];
+        let lines = complete_lines(&mut line);
+        assert_eq!(lines.len(), 2 * MAX_PREFIXED_LINE + 2);
+        assert_eq!(lines.iter().filter(|&&byte| byte == b'\n').count(), 2);
+        assert_eq!(lines[MAX_PREFIXED_LINE], b'\n');
+        assert_eq!(line, b"a");
+    }
+}
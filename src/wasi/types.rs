@@ -2,7 +2,7 @@
 
 #![allow(dead_code)]
 
-use std::io::{IoSlice, IoSliceMut};
+use std::io::{self, IoSlice, IoSliceMut};
 use std::mem::size_of;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 use std::str;
@@ -202,27 +202,35 @@ impl WasiString {
     }
 }
 
-pub struct WasiEnvVars {
+/// A list of null-terminated strings, as passed to the guest by `args_get` and `environ_get`.
+pub struct WasiStrings {
     bytes: Vec<Vec<u8>>,
     total_bytes: u32,
 }
 
-impl WasiEnvVars {
-    pub fn new(vars: impl Iterator<Item = (String, String)>) -> Self {
-        let mut bytes = vec![];
-        for (k, v) in vars {
-            bytes.push(format!("{}={}\0", k, v).into_bytes());
-        }
-
+impl WasiStrings {
+    pub fn new(strings: impl Iterator<Item = String>) -> Self {
+        let bytes: Vec<Vec<u8>> = strings
+            .map(|string| format!("{}\0", string).into_bytes())
+            .collect();
         let total_bytes = bytes.iter().map(|v| v.len() as u32).sum();
 
         Self { bytes, total_bytes }
     }
 
+    /// Environment variables are passed as `key=value` strings.
+    pub fn env_vars(vars: impl Iterator<Item = (String, String)>) -> Self {
+        Self::new(vars.map(|(k, v)| format!("{}={}", k, v)))
+    }
+
     pub fn len(&self) -> u32 {
         self.bytes.len() as u32
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
     pub fn total_bytes(&self) -> u32 {
         self.total_bytes
     }
@@ -232,6 +240,30 @@ impl WasiEnvVars {
     }
 }
 
+/// Maps an `io::Error` returned by the host to the closest WASI errno value.
+pub fn io_error_to_errno(error: &io::Error) -> u32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => WASI_ENOENT,
+        io::ErrorKind::PermissionDenied => WASI_EACCES,
+        io::ErrorKind::ConnectionRefused => WASI_ECONNREFUSED,
+        io::ErrorKind::ConnectionReset => WASI_ECONNRESET,
+        io::ErrorKind::ConnectionAborted => WASI_ECONNABORTED,
+        io::ErrorKind::NotConnected => WASI_ENOTCONN,
+        io::ErrorKind::AddrInUse => WASI_EADDRINUSE,
+        io::ErrorKind::AddrNotAvailable => WASI_EADDRNOTAVAIL,
+        io::ErrorKind::BrokenPipe => WASI_EPIPE,
+        io::ErrorKind::AlreadyExists => WASI_EEXIST,
+        io::ErrorKind::WouldBlock => WASI_EAGAIN,
+        io::ErrorKind::InvalidInput => WASI_EINVAL,
+        io::ErrorKind::InvalidData => WASI_EILSEQ,
+        io::ErrorKind::TimedOut => WASI_ETIMEDOUT,
+        io::ErrorKind::WriteZero => WASI_EIO,
+        io::ErrorKind::Interrupted => WASI_EINTR,
+        io::ErrorKind::UnexpectedEof => WASI_EIO,
+        _ => WASI_EIO,
+    }
+}
+
 pub const WASI_ESUCCESS: u32 = 0;
 pub const WASI_E2BIG: u32 = 1;
 pub const WASI_EACCES: u32 = 2;
@@ -313,3 +345,86 @@ pub const WASI_ENOTCAPABLE: u32 = 76;
 pub const WASI_STDIN_FILENO: u32 = 0;
 pub const WASI_STDOUT_FILENO: u32 = 1;
 pub const WASI_STDERR_FILENO: u32 = 2;
+
+pub const WASI_SOCK_RECV_PEEK: u32 = 1 << 0;
+pub const WASI_SOCK_RECV_WAITALL: u32 = 1 << 1;
+pub const WASI_SOCK_RECV_DATA_TRUNCATED: u16 = 1 << 0;
+
+pub const WASI_SHUT_RD: u32 = 1 << 0;
+pub const WASI_SHUT_WR: u32 = 1 << 1;
+
+pub const WASI_FILETYPE_UNKNOWN: u8 = 0;
+pub const WASI_FILETYPE_BLOCK_DEVICE: u8 = 1;
+pub const WASI_FILETYPE_CHARACTER_DEVICE: u8 = 2;
+pub const WASI_FILETYPE_DIRECTORY: u8 = 3;
+pub const WASI_FILETYPE_REGULAR_FILE: u8 = 4;
+pub const WASI_FILETYPE_SOCKET_DGRAM: u8 = 5;
+pub const WASI_FILETYPE_SOCKET_STREAM: u8 = 6;
+pub const WASI_FILETYPE_SYMBOLIC_LINK: u8 = 7;
+
+pub const WASI_O_CREAT: u32 = 1 << 0;
+pub const WASI_O_DIRECTORY: u32 = 1 << 1;
+pub const WASI_O_EXCL: u32 = 1 << 2;
+pub const WASI_O_TRUNC: u32 = 1 << 3;
+
+pub const WASI_FDFLAG_APPEND: u32 = 1 << 0;
+pub const WASI_FDFLAG_DSYNC: u32 = 1 << 1;
+pub const WASI_FDFLAG_NONBLOCK: u32 = 1 << 2;
+pub const WASI_FDFLAG_RSYNC: u32 = 1 << 3;
+pub const WASI_FDFLAG_SYNC: u32 = 1 << 4;
+
+pub const WASI_LOOKUP_SYMLINK_FOLLOW: u32 = 1 << 0;
+
+pub const WASI_WHENCE_SET: u32 = 0;
+pub const WASI_WHENCE_CUR: u32 = 1;
+pub const WASI_WHENCE_END: u32 = 2;
+
+pub const WASI_RIGHT_FD_READ: u64 = 1 << 1;
+pub const WASI_RIGHT_FD_SEEK: u64 = 1 << 2;
+pub const WASI_RIGHT_FD_FDSTAT_SET_FLAGS: u64 = 1 << 3;
+pub const WASI_RIGHT_FD_TELL: u64 = 1 << 5;
+pub const WASI_RIGHT_FD_WRITE: u64 = 1 << 6;
+pub const WASI_RIGHT_PATH_CREATE_FILE: u64 = 1 << 10;
+pub const WASI_RIGHT_PATH_OPEN: u64 = 1 << 13;
+pub const WASI_RIGHT_FD_READDIR: u64 = 1 << 14;
+pub const WASI_RIGHT_PATH_FILESTAT_GET: u64 = 1 << 18;
+pub const WASI_RIGHT_FD_FILESTAT_GET: u64 = 1 << 21;
+pub const WASI_RIGHT_POLL_FD_READWRITE: u64 = 1 << 27;
+pub const WASI_RIGHT_SOCK_SHUTDOWN: u64 = 1 << 28;
+pub const WASI_RIGHT_SOCK_ACCEPT: u64 = 1 << 29;
+
+/// Size of the `fdstat` struct in guest memory.
+pub const WASI_FDSTAT_SIZE: usize = 24;
+
+/// Size of the `filestat` struct in guest memory.
+pub const WASI_FILESTAT_SIZE: usize = 64;
+/// Size of the `dirent` struct in guest memory, the name follows it.
+pub const WASI_DIRENT_SIZE: usize = 24;
+
+pub const WASI_CLOCK_REALTIME: u32 = 0;
+pub const WASI_CLOCK_MONOTONIC: u32 = 1;
+pub const WASI_CLOCK_PROCESS_CPUTIME_ID: u32 = 2;
+pub const WASI_CLOCK_THREAD_CPUTIME_ID: u32 = 3;
+
+pub const WASI_EVENTTYPE_CLOCK: u8 = 0;
+pub const WASI_EVENTTYPE_FD_READ: u8 = 1;
+pub const WASI_EVENTTYPE_FD_WRITE: u8 = 2;
+
+pub const WASI_SUBSCRIPTION_CLOCK_ABSTIME: u16 = 1 << 0;
+pub const WASI_EVENT_FD_READWRITE_HANGUP: u16 = 1 << 0;
+
+/// Size of the `subscription` struct in guest memory.
+pub const WASI_SUBSCRIPTION_SIZE: usize = 48;
+/// Size of the `event` struct in guest memory.
+pub const WASI_EVENT_SIZE: usize = 32;
+
+// `wasi_unstable` (preview0) numbers `whence` differently and lays out `filestat` and
+// `subscription` differently, all other types are shared with preview1.
+pub const WASI_UNSTABLE_WHENCE_CUR: u32 = 0;
+pub const WASI_UNSTABLE_WHENCE_END: u32 = 1;
+pub const WASI_UNSTABLE_WHENCE_SET: u32 = 2;
+
+/// Size of the preview0 `filestat` struct in guest memory.
+pub const WASI_UNSTABLE_FILESTAT_SIZE: usize = 56;
+/// Size of the preview0 `subscription` struct in guest memory.
+pub const WASI_UNSTABLE_SUBSCRIPTION_SIZE: usize = 56;
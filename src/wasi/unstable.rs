@@ -0,0 +1,225 @@
+//! Support for modules compiled against `wasi_unstable` (WASI preview0).
+//!
+//! Most preview0 functions are identical to their preview1 counterparts and are forwarded to
+//! `WasiState`. Only `fd_seek` (different `whence` values), the filestat functions and
+//! `poll_oneoff` (different struct layouts) need to be translated. Both namespaces share the state
+//! of the process, e.g. its file descriptor table.
+
+use std::convert::TryInto;
+use std::io::{IoSlice, IoSliceMut};
+use std::ops::{Deref, DerefMut};
+
+use uptown_funk::host_functions;
+
+use super::api::{ExitCode, Ptr, WasiState};
+use super::poll::Subscription;
+use super::types::*;
+
+pub struct WasiUnstableState(WasiState);
+
+impl WasiUnstableState {
+    /// `state` is usually shared with the preview1 namespace, see `WasiState::share`.
+    pub fn new(state: WasiState) -> Self {
+        Self(state)
+    }
+}
+
+// Arguments shared with preview1 (e.g. `Ptr`) are created from a `WasiState`.
+impl Deref for WasiUnstableState {
+    type Target = WasiState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for WasiUnstableState {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[host_functions(namespace = "wasi_unstable")]
+impl WasiUnstableState {
+    fn proc_exit(&self, exit_code: ExitCode) {
+        self.0.proc_exit(exit_code)
+    }
+
+    async fn fd_write<'a>(&'a mut self, fd: u32, ciovs: &'a [IoSlice<'a>]) -> (u32, u32) {
+        self.0.fd_write(fd, ciovs).await
+    }
+
+    async fn fd_read<'a>(&'a mut self, fd: u32, iovs: &'a mut [IoSliceMut<'a>]) -> (u32, u32) {
+        self.0.fd_read(fd, iovs).await
+    }
+
+    async fn path_open(
+        &mut self,
+        dirfd: u32,
+        dirflags: u32,
+        path: &str,
+        oflags: u32,
+        fs_rights_base: i64,
+        fs_rights_inheriting: i64,
+        fdflags: u32,
+    ) -> (u32, u32) {
+        self.0
+            .path_open(
+                dirfd,
+                dirflags,
+                path,
+                oflags,
+                fs_rights_base,
+                fs_rights_inheriting,
+                fdflags,
+            )
+            .await
+    }
+
+    fn fd_close(&mut self, fd: u32) -> u32 {
+        self.0.fd_close(fd)
+    }
+
+    fn fd_seek(&mut self, fd: u32, offset: i64, whence: u32) -> (u32, u64) {
+        let whence = match whence {
+            WASI_UNSTABLE_WHENCE_SET => WASI_WHENCE_SET,
+            WASI_UNSTABLE_WHENCE_CUR => WASI_WHENCE_CUR,
+            WASI_UNSTABLE_WHENCE_END => WASI_WHENCE_END,
+            _ => return (WASI_EINVAL, 0),
+        };
+        self.0.fd_seek(fd, offset, whence)
+    }
+
+    fn fd_fdstat_get(&self, fd: u32, buf: Ptr<u8>) -> u32 {
+        self.0.fd_fdstat_get(fd, buf)
+    }
+
+    fn fd_fdstat_set_flags(&mut self, fd: u32, flags: u32) -> u32 {
+        self.0.fd_fdstat_set_flags(fd, flags)
+    }
+
+    fn fd_filestat_get(&self, fd: u32, buf: Ptr<u8>) -> u32 {
+        match self.0.fd_filestat(fd) {
+            Ok(filestat) => {
+                buf.copy_slice(&filestat.to_unstable_bytes());
+                WASI_ESUCCESS
+            }
+            Err(errno) => errno,
+        }
+    }
+
+    async fn path_filestat_get(&self, dirfd: u32, flags: u32, path: &str, buf: Ptr<'_, u8>) -> u32 {
+        match self.0.path_filestat(dirfd, flags, path).await {
+            Ok(filestat) => {
+                buf.copy_slice(&filestat.to_unstable_bytes());
+                WASI_ESUCCESS
+            }
+            Err(errno) => errno,
+        }
+    }
+
+    async fn fd_readdir<'a>(&'a self, fd: u32, buf: &'a mut [u8], cookie: i64) -> (u32, u32) {
+        self.0.fd_readdir(fd, buf, cookie).await
+    }
+
+    fn fd_prestat_get(&self, fd: u32, prestat: Ptr<u32>) -> u32 {
+        self.0.fd_prestat_get(fd, prestat)
+    }
+
+    fn fd_prestat_dir_name(&self, fd: u32, path: &mut [u8]) -> u32 {
+        self.0.fd_prestat_dir_name(fd, path)
+    }
+
+    fn args_sizes_get(&self, arg_count: Ptr<u32>, total_bytes: Ptr<u32>) -> u32 {
+        self.0.args_sizes_get(arg_count, total_bytes)
+    }
+
+    fn args_get<'a>(&self, argv: Ptr<Ptr<'a, u8>>, argv_buf: Ptr<'a, u8>) -> u32 {
+        self.0.args_get(argv, argv_buf)
+    }
+
+    fn environ_sizes_get(&self, var_count: Ptr<u32>, total_bytes: Ptr<u32>) -> u32 {
+        self.0.environ_sizes_get(var_count, total_bytes)
+    }
+
+    fn environ_get<'a>(&self, environ: Ptr<Ptr<'a, u8>>, environ_buf: Ptr<'a, u8>) -> u32 {
+        self.0.environ_get(environ, environ_buf)
+    }
+
+    async fn sock_recv<'a>(
+        &'a mut self,
+        fd: u32,
+        ri_data: &'a mut [IoSliceMut<'a>],
+        ri_flags: u32,
+        ro_datalen: Ptr<'a, u32>,
+        ro_flags: Ptr<'a, u16>,
+    ) -> u32 {
+        self.0
+            .sock_recv(fd, ri_data, ri_flags, ro_datalen, ro_flags)
+            .await
+    }
+
+    async fn sock_send<'a>(
+        &'a mut self,
+        fd: u32,
+        si_data: &'a [IoSlice<'a>],
+        si_flags: u32,
+    ) -> (u32, u32) {
+        self.0.sock_send(fd, si_data, si_flags).await
+    }
+
+    fn sock_shutdown(&mut self, fd: u32, how: u32) -> u32 {
+        self.0.sock_shutdown(fd, how)
+    }
+
+    fn clock_res_get(&self, id: u32) -> (u32, u64) {
+        self.0.clock_res_get(id)
+    }
+
+    fn clock_time_get(&self, id: u32, precision: i64) -> (u32, u64) {
+        self.0.clock_time_get(id, precision)
+    }
+
+    fn random_get(&self, buf: &mut [u8]) -> u32 {
+        self.0.random_get(buf)
+    }
+
+    // Events have the same layout in both versions.
+    async fn poll_oneoff(
+        &mut self,
+        subscriptions: Ptr<'_, u8>,
+        events: Ptr<'_, u8>,
+        nsubscriptions: u32,
+    ) -> (u32, u32) {
+        let size = nsubscriptions as usize * WASI_UNSTABLE_SUBSCRIPTION_SIZE;
+        let subscriptions = match subscriptions.slice(size) {
+            Some(subscriptions) if size > 0 => subscriptions,
+            Some(_) => return (WASI_EINVAL, 0),
+            None => return (WASI_EFAULT, 0),
+        };
+        // Events have the same layout in both namespaces.
+        if events
+            .slice(nsubscriptions as usize * WASI_EVENT_SIZE)
+            .is_none()
+        {
+            return (WASI_EFAULT, 0);
+        }
+        let subscriptions: Option<Vec<Subscription>> = subscriptions
+            .chunks(WASI_UNSTABLE_SUBSCRIPTION_SIZE)
+            .map(|bytes| Subscription::parse_unstable(bytes.try_into().unwrap()))
+            .collect();
+        let subscriptions = match subscriptions {
+            Some(subscriptions) => subscriptions,
+            None => return (WASI_EINVAL, 0),
+        };
+
+        let ready = self.0.poll(subscriptions).await;
+        let bytes: Vec<u8> = ready
+            .iter()
+            .flat_map(|event| event.to_bytes().to_vec())
+            .collect();
+        // Can't go out of bounds, the buffer was checked before waiting.
+        events.copy_slice(&bytes);
+        (WASI_ESUCCESS, ready.len() as u32)
+    }
+}
@@ -0,0 +1,828 @@
+//! In-memory directories exposed to guests through WASI preopens.
+//!
+//! A virtual directory is seeded from a host directory or a tar archive when it's configured and
+//! shows up in the same file descriptor table as host directories. Nothing the guest writes
+//! reaches the host. Each spawned process works on its own copy of the tree, which starts out as
+//! the seed or, for copy-on-write directories, as the tree of its parent at the time of spawning.
+//! Files and directories are reference counted and only copied once they are modified, so
+//! spawning processes with large trees stays cheap.
+//!
+//! Virtual directories only contain regular files and directories, there are no symbolic links.
+//! Writes are limited in how large a single file and all files of a tree can get, see `Limits`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, IoSlice, IoSliceMut, Read, SeekFrom};
+use std::path::{Component, Path};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+
+use super::filesystem::{split_mapping, write_dirents, Filestat};
+use super::types::*;
+
+#[derive(Clone)]
+enum Node {
+    File(Arc<Vec<u8>>),
+    Directory(Arc<BTreeMap<String, Node>>),
+}
+
+// The tree of one process, shared by all descriptors pointing into it.
+type Tree = Arc<Mutex<Root>>;
+
+struct Root {
+    node: Node,
+    // The size of all files in the tree.
+    size: u64,
+    limits: Limits,
+}
+
+impl Root {
+    fn new_tree(node: Node, limits: Limits) -> Tree {
+        Arc::new(Mutex::new(Root {
+            size: node.total_size(),
+            node,
+            limits,
+        }))
+    }
+}
+
+/// How large the files of a virtual directory can grow through writes of the guest. Writing past
+/// `max_file_size` fails with `WASI_EFBIG`, growing the whole tree past `max_size` with
+/// `WASI_ENOSPC`. The seed counts towards the size of the tree, but isn't limited itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_file_size: u64,
+    pub max_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_size: 256 * 1024 * 1024,
+            max_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+// Archives can claim any entry size, so memory for the contents is only reserved up to this size.
+const MAX_PREALLOCATION: u64 = 1024 * 1024;
+
+impl Node {
+    fn empty_directory() -> Self {
+        Node::Directory(Arc::new(BTreeMap::new()))
+    }
+
+    fn filetype(&self) -> u8 {
+        match self {
+            Node::File(_) => WASI_FILETYPE_REGULAR_FILE,
+            Node::Directory(_) => WASI_FILETYPE_DIRECTORY,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Node::File(contents) => contents.len() as u64,
+            Node::Directory(entries) => entries.len() as u64,
+        }
+    }
+
+    fn total_size(&self) -> u64 {
+        match self {
+            Node::File(contents) => contents.len() as u64,
+            Node::Directory(entries) => entries.values().map(Node::total_size).sum(),
+        }
+    }
+
+    fn get(&self, path: &[String]) -> Option<&Node> {
+        path.iter().try_fold(self, |node, name| match node {
+            Node::Directory(entries) => entries.get(name),
+            Node::File(_) => None,
+        })
+    }
+
+    // Nodes along the path that are shared with other trees are copied.
+    fn get_mut(&mut self, path: &[String]) -> Option<&mut Node> {
+        let mut node = self;
+        for name in path {
+            node = match node {
+                Node::Directory(entries) => Arc::make_mut(entries).get_mut(name)?,
+                Node::File(_) => return None,
+            };
+        }
+        Some(node)
+    }
+
+    // Returns the entries of the directory at `path`, creating missing directories on the way.
+    fn create_dirs(&mut self, path: &[String]) -> Result<&mut BTreeMap<String, Node>, io::Error> {
+        let mut node = self;
+        for name in path {
+            node = match node {
+                Node::Directory(entries) => Arc::make_mut(entries)
+                    .entry(name.clone())
+                    .or_insert_with(Node::empty_directory),
+                Node::File(_) => break,
+            };
+        }
+        match node {
+            Node::Directory(entries) => Ok(Arc::make_mut(entries)),
+            Node::File(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a directory", path.join("/")),
+            )),
+        }
+    }
+
+    fn insert_file(&mut self, path: &[String], contents: Vec<u8>) -> Result<(), io::Error> {
+        if let Some((name, parent)) = path.split_last() {
+            self.create_dirs(parent)?
+                .insert(name.clone(), Node::File(Arc::new(contents)));
+        }
+        Ok(())
+    }
+}
+
+/// An in-memory directory that is made accessible to the guest under `guest_path`.
+///
+/// Parsed from strings of the form `source[::guest]`, where the source is a host directory or a
+/// `.tar` archive the tree is seeded with. An empty source creates an empty directory. If the
+/// guest path is omitted, the directory is visible under the source path.
+///
+/// Processes spawned from the guest get their own tree, but processes spawned by the host with
+/// clones of the same `VirtualDir` share one. Use `for_child` to give them separate trees.
+#[derive(Clone)]
+pub struct VirtualDir {
+    guest_path: String,
+    seed: Node,
+    tree: Tree,
+    copy_on_write: bool,
+    limits: Limits,
+}
+
+impl VirtualDir {
+    /// Creates an empty directory.
+    pub fn new(guest_path: String) -> Self {
+        Self::with_seed(Node::empty_directory(), guest_path)
+    }
+
+    /// Copies the files and directories of `host_path` into memory. Symbolic links and special
+    /// files are skipped.
+    pub fn from_host_dir(
+        host_path: impl AsRef<Path>,
+        guest_path: String,
+    ) -> Result<Self, io::Error> {
+        let seed = read_host_dir(host_path.as_ref())?;
+        Ok(Self::with_seed(seed, guest_path))
+    }
+
+    /// Copies the files and directories of a tar archive into memory. Other entries, like links,
+    /// are skipped.
+    pub fn from_tar(archive: impl Read, guest_path: String) -> Result<Self, io::Error> {
+        let mut seed = Node::empty_directory();
+        for entry in tar::Archive::new(archive).entries()? {
+            let mut entry = entry?;
+            let path = archive_path(&entry.path()?)?;
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                seed.create_dirs(&path)?;
+            } else if entry_type.is_file() {
+                let mut contents = Vec::with_capacity(entry.size().min(MAX_PREALLOCATION) as usize);
+                entry.read_to_end(&mut contents)?;
+                seed.insert_file(&path, contents)?;
+            }
+        }
+        Ok(Self::with_seed(seed, guest_path))
+    }
+
+    fn with_seed(seed: Node, guest_path: String) -> Self {
+        let limits = Limits::default();
+        Self {
+            guest_path,
+            tree: Root::new_tree(seed.clone(), limits),
+            seed,
+            copy_on_write: false,
+            limits,
+        }
+    }
+
+    /// Children start with the tree of their parent at the time they are spawned, instead of
+    /// the seed.
+    pub fn copy_on_write(mut self) -> Self {
+        self.copy_on_write = true;
+        self
+    }
+
+    /// Adds a file to the seed, creating missing parent directories. `path` is relative to the
+    /// root of the directory.
+    pub fn with_file(
+        mut self,
+        path: &str,
+        contents: impl Into<Vec<u8>>,
+    ) -> Result<Self, io::Error> {
+        let path = archive_path(Path::new(path))?;
+        self.seed.insert_file(&path, contents.into())?;
+        self.tree = Root::new_tree(self.seed.clone(), self.limits);
+        Ok(self)
+    }
+
+    /// Limits how large files can grow through writes of the guest.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self.tree = Root::new_tree(self.seed.clone(), limits);
+        self
+    }
+
+    pub fn guest_path(&self) -> &str {
+        &self.guest_path
+    }
+
+    /// Returns the current contents of a file in the tree, e.g. to check what a process wrote.
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        let path = archive_path(Path::new(path)).ok()?;
+        match self.tree.lock().unwrap().node.get(&path) {
+            Some(Node::File(contents)) => Some(contents.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// The directory handed to a newly spawned child process, with a tree of its own.
+    pub fn for_child(&self) -> Self {
+        let root = if self.copy_on_write {
+            self.tree.lock().unwrap().node.clone()
+        } else {
+            self.seed.clone()
+        };
+        Self {
+            guest_path: self.guest_path.clone(),
+            seed: self.seed.clone(),
+            tree: Root::new_tree(root, self.limits),
+            copy_on_write: self.copy_on_write,
+            limits: self.limits,
+        }
+    }
+}
+
+impl FromStr for VirtualDir {
+    type Err = Error;
+
+    fn from_str(dir: &str) -> Result<Self, Self::Err> {
+        let (source, guest) = split_mapping(dir);
+        let guest = guest.to_owned();
+        let dir = if source.is_empty() {
+            Ok(Self::new(guest))
+        } else if source.ends_with(".tar") {
+            File::open(source).and_then(|archive| Self::from_tar(archive, guest))
+        } else {
+            Self::from_host_dir(source, guest)
+        };
+        dir.map_err(|err| anyhow!("Can't load virtual directory `{}`: {}", source, err))
+    }
+}
+
+fn read_host_dir(host_path: &Path) -> Result<Node, io::Error> {
+    let mut entries = BTreeMap::new();
+    for entry in fs::read_dir(host_path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let node = if file_type.is_dir() {
+            read_host_dir(&entry.path())?
+        } else if file_type.is_file() {
+            Node::File(Arc::new(fs::read(entry.path())?))
+        } else {
+            continue;
+        };
+        entries.insert(entry.file_name().to_string_lossy().into_owned(), node);
+    }
+    Ok(Node::Directory(Arc::new(entries)))
+}
+
+// Splits a path inside of an archive into its components. Absolute paths are treated as relative
+// to the root of the archive, paths leaving it are rejected.
+fn archive_path(path: &Path) -> Result<Vec<String>, io::Error> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy().into_owned()),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("`{}` points outside of the directory", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(components)
+}
+
+// Virtual nodes don't have inodes, so they are derived from the tree and the path.
+fn inode(tree: &Tree, path: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (Arc::as_ptr(tree) as usize).hash(&mut hasher);
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn filestat(tree: &Tree, path: &[String], node: &Node) -> Filestat {
+    Filestat {
+        inode: inode(tree, path),
+        size: node.size(),
+        ..Filestat::of_type(node.filetype())
+    }
+}
+
+/// How a file is opened, the equivalent of `std::fs::OpenOptions`. Also used for host files.
+#[derive(Clone, Copy, Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub truncate: bool,
+    pub create: bool,
+    pub create_new: bool,
+}
+
+/// An open directory inside of a virtual directory.
+#[derive(Clone)]
+pub struct VirtualDirectory {
+    tree: Tree,
+    path: Vec<String>,
+    // Only set for preopened directories.
+    guest_path: Option<String>,
+}
+
+impl VirtualDirectory {
+    pub fn preopened(dir: &VirtualDir) -> Self {
+        Self {
+            tree: dir.tree.clone(),
+            path: Vec::new(),
+            guest_path: Some(dir.guest_path.clone()),
+        }
+    }
+
+    /// Returns the path under which the directory was preopened, `None` if it wasn't.
+    pub fn guest_path(&self) -> Option<&str> {
+        self.guest_path.as_deref()
+    }
+
+    /// Resolves the guest `path` relative to this directory to a path from the root of the tree.
+    /// Fails with `WASI_ENOTCAPABLE` if the path escapes the root.
+    pub fn resolve(&self, path: &str) -> Result<Vec<String>, u32> {
+        let mut resolved = self.path.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name.to_string_lossy().into_owned()),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if resolved.pop().is_none() {
+                        return Err(WASI_ENOTCAPABLE);
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(WASI_ENOTCAPABLE),
+            }
+        }
+        Ok(resolved)
+    }
+
+    pub fn is_dir(&self, path: &[String]) -> bool {
+        matches!(
+            self.tree.lock().unwrap().node.get(path),
+            Some(Node::Directory(_))
+        )
+    }
+
+    /// Opens a subdirectory that was previously resolved with `resolve`.
+    pub fn open_dir(&self, path: Vec<String>) -> Result<Self, u32> {
+        match self.tree.lock().unwrap().node.get(&path) {
+            Some(Node::Directory(_)) => {}
+            Some(Node::File(_)) => return Err(WASI_ENOTDIR),
+            None => return Err(WASI_ENOENT),
+        }
+        Ok(Self {
+            tree: self.tree.clone(),
+            path,
+            guest_path: None,
+        })
+    }
+
+    /// Opens a file that was previously resolved with `resolve`.
+    pub fn open_file(&self, path: Vec<String>, options: OpenOptions) -> Result<VirtualFile, u32> {
+        let mut root = self.tree.lock().unwrap();
+        let root = &mut *root;
+        match root.node.get(&path).map(Node::filetype) {
+            Some(WASI_FILETYPE_DIRECTORY) => return Err(WASI_EISDIR),
+            Some(_) if options.create_new => return Err(WASI_EEXIST),
+            Some(_) => {
+                if options.truncate {
+                    if let Some(Node::File(contents)) = root.node.get_mut(&path) {
+                        root.size -= contents.len() as u64;
+                        *contents = Arc::new(Vec::new());
+                    }
+                }
+            }
+            None if options.create || options.create_new => {
+                let (name, parent) = path.split_last().ok_or(WASI_EISDIR)?;
+                match root.node.get_mut(parent) {
+                    Some(Node::Directory(entries)) => {
+                        let file = Node::File(Arc::new(Vec::new()));
+                        Arc::make_mut(entries).insert(name.clone(), file);
+                    }
+                    Some(Node::File(_)) => return Err(WASI_ENOTDIR),
+                    None => return Err(WASI_ENOENT),
+                }
+            }
+            None => return Err(WASI_ENOENT),
+        }
+        Ok(VirtualFile {
+            tree: self.tree.clone(),
+            path,
+            position: 0,
+            options,
+        })
+    }
+
+    pub fn filestat(&self) -> Result<Filestat, u32> {
+        self.path_filestat(&self.path)
+    }
+
+    /// Returns the attributes of a path that was previously resolved with `resolve`.
+    pub fn path_filestat(&self, path: &[String]) -> Result<Filestat, u32> {
+        match self.tree.lock().unwrap().node.get(path) {
+            Some(node) => Ok(filestat(&self.tree, path, node)),
+            None => Err(WASI_ENOENT),
+        }
+    }
+
+    /// Writes the directory entries starting at `cookie` into `buffer`, see `write_dirents`.
+    pub fn read_entries(&self, buffer: &mut [u8], cookie: u64) -> Result<usize, u32> {
+        let root = self.tree.lock().unwrap();
+        let entries = match root.node.get(&self.path) {
+            Some(Node::Directory(entries)) => entries,
+            Some(Node::File(_)) => return Err(WASI_ENOTDIR),
+            None => return Err(WASI_ENOENT),
+        };
+        let parent = &self.path[..self.path.len().saturating_sub(1)];
+        let mut dirents = vec![
+            (
+                ".".to_owned(),
+                inode(&self.tree, &self.path),
+                WASI_FILETYPE_DIRECTORY,
+            ),
+            (
+                "..".to_owned(),
+                inode(&self.tree, parent),
+                WASI_FILETYPE_DIRECTORY,
+            ),
+        ];
+        // Entries are sorted by name, so cookies stay valid between calls.
+        for (name, node) in entries.iter() {
+            let mut path = self.path.clone();
+            path.push(name.clone());
+            dirents.push((name.clone(), inode(&self.tree, &path), node.filetype()));
+        }
+        let dirents = dirents
+            .iter()
+            .map(|(name, inode, filetype)| (name.as_str(), *inode, *filetype));
+        Ok(write_dirents(buffer, cookie, dirents))
+    }
+}
+
+/// An open file inside of a virtual directory.
+pub struct VirtualFile {
+    tree: Tree,
+    path: Vec<String>,
+    position: u64,
+    options: OpenOptions,
+}
+
+impl VirtualFile {
+    pub fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, u32> {
+        if !self.options.read {
+            return Err(WASI_EBADF);
+        }
+        let root = self.tree.lock().unwrap();
+        let contents = match root.node.get(&self.path) {
+            Some(Node::File(contents)) => contents,
+            _ => return Err(WASI_ENOENT),
+        };
+        let mut read = 0;
+        for buf in bufs {
+            let start = self.position.min(contents.len() as u64) as usize;
+            let len = buf.len().min(contents.len() - start);
+            buf[..len].copy_from_slice(&contents[start..start + len]);
+            self.position += len as u64;
+            read += len;
+        }
+        Ok(read)
+    }
+
+    pub fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, u32> {
+        if !self.options.write && !self.options.append {
+            return Err(WASI_EBADF);
+        }
+        let mut root = self.tree.lock().unwrap();
+        let root = &mut *root;
+        let contents = match root.node.get_mut(&self.path) {
+            Some(Node::File(contents)) => contents,
+            _ => return Err(WASI_ENOENT),
+        };
+        let len = contents.len() as u64;
+        if self.options.append {
+            self.position = len;
+        }
+        let written = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        let end = match self.position.checked_add(written) {
+            Some(end) if end <= root.limits.max_file_size => end,
+            _ => return Err(WASI_EFBIG),
+        };
+        let grown = end.saturating_sub(len);
+        if root.size.saturating_add(grown) > root.limits.max_size {
+            return Err(WASI_ENOSPC);
+        }
+        let end = usize::try_from(end).map_err(|_| WASI_EFBIG)?;
+
+        let contents = Arc::make_mut(contents);
+        // Writing past the end fills the gap with zeros.
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        let mut position = self.position as usize;
+        for buf in bufs {
+            contents[position..position + buf.len()].copy_from_slice(buf);
+            position += buf.len();
+        }
+        root.size += grown;
+        self.position = end as u64;
+        Ok(written as usize)
+    }
+
+    pub fn seek(&mut self, position: SeekFrom) -> Result<u64, u32> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+            SeekFrom::End(offset) => add_offset(self.len(), offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(WASI_EINVAL),
+        }
+    }
+
+    /// The number of bytes between the current position and the end of the file.
+    pub fn remaining(&self) -> u64 {
+        self.len().saturating_sub(self.position)
+    }
+
+    pub fn filestat(&self) -> Result<Filestat, u32> {
+        match self.tree.lock().unwrap().node.get(&self.path) {
+            Some(node) => Ok(filestat(&self.tree, &self.path, node)),
+            None => Err(WASI_ENOENT),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self.tree.lock().unwrap().node.get(&self.path) {
+            Some(node) => node.size(),
+            None => 0,
+        }
+    }
+}
+
+fn add_offset(position: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        position.checked_add(offset as u64)
+    } else {
+        position.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn data() -> VirtualDir {
+        VirtualDir::new("/data".to_owned())
+            .with_file("file", "file")
+            .unwrap()
+            .with_file("dir/nested", "nested")
+            .unwrap()
+    }
+
+    fn open(dir: &VirtualDir, path: &str, options: OpenOptions) -> Result<VirtualFile, u32> {
+        let root = VirtualDirectory::preopened(dir);
+        root.open_file(root.resolve(path)?, options)
+    }
+
+    fn read_write() -> OpenOptions {
+        OpenOptions {
+            read: true,
+            write: true,
+            ..OpenOptions::default()
+        }
+    }
+
+    fn write(file: &mut VirtualFile, contents: &str) -> Result<usize, u32> {
+        file.write_vectored(&[IoSlice::new(contents.as_bytes())])
+    }
+
+    fn read(file: &mut VirtualFile, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        let read = file
+            .read_vectored(&mut [IoSliceMut::new(&mut buffer)])
+            .unwrap();
+        buffer.truncate(read);
+        buffer
+    }
+
+    #[test]
+    fn paths_escaping_the_root() {
+        let root = VirtualDirectory::preopened(&data());
+        assert_eq!(root.resolve("../file"), Err(WASI_ENOTCAPABLE));
+        assert_eq!(root.resolve("dir/../../file"), Err(WASI_ENOTCAPABLE));
+        assert_eq!(root.resolve("/file"), Err(WASI_ENOTCAPABLE));
+        assert_eq!(root.resolve("dir/../file"), Ok(vec!["file".to_owned()]));
+
+        let dir = root.open_dir(root.resolve("dir").unwrap()).unwrap();
+        assert_eq!(dir.resolve("../../file"), Err(WASI_ENOTCAPABLE));
+        assert_eq!(dir.resolve("../file"), Ok(vec!["file".to_owned()]));
+
+        assert!(archive_path(Path::new("../file")).is_err());
+        assert!(archive_path(Path::new("dir/../../file")).is_err());
+        // Absolute paths in archives are relative to the root of the archive.
+        assert_eq!(
+            archive_path(Path::new("/dir/./file")).unwrap(),
+            vec!["dir".to_owned(), "file".to_owned()]
+        );
+        assert!(VirtualDir::new("/data".to_owned())
+            .with_file("../file", "file")
+            .is_err());
+    }
+
+    #[test]
+    fn children_start_from_the_seed() {
+        let parent = data();
+        let mut file = open(&parent, "file", read_write()).unwrap();
+        write(&mut file, "parent").unwrap();
+
+        let child = parent.for_child();
+        assert_eq!(child.read_file("file").unwrap(), b"file");
+        let mut file = open(&child, "file", read_write()).unwrap();
+        write(&mut file, "child").unwrap();
+        assert_eq!(parent.read_file("file").unwrap(), b"parent");
+    }
+
+    #[test]
+    fn copy_on_write_children_start_from_the_parent() {
+        let parent = data().copy_on_write();
+        let mut file = open(&parent, "file", read_write()).unwrap();
+        write(&mut file, "parent").unwrap();
+
+        let child = parent.for_child();
+        assert_eq!(child.read_file("file").unwrap(), b"parent");
+
+        // Writes on either side stay on that side.
+        let mut file = open(&child, "file", read_write()).unwrap();
+        write(&mut file, "child!").unwrap();
+        let mut file = open(&parent, "dir/nested", read_write()).unwrap();
+        write(&mut file, "parent").unwrap();
+        assert_eq!(parent.read_file("file").unwrap(), b"parent");
+        assert_eq!(child.read_file("file").unwrap(), b"child!");
+        assert_eq!(child.read_file("dir/nested").unwrap(), b"nested");
+    }
+
+    #[test]
+    fn open_options() {
+        let dir = data();
+        let append = OpenOptions {
+            append: true,
+            ..OpenOptions::default()
+        };
+        let mut file = open(&dir, "file", append).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        write(&mut file, "!").unwrap();
+        assert_eq!(dir.read_file("file").unwrap(), b"file!");
+
+        let truncate = OpenOptions {
+            write: true,
+            truncate: true,
+            ..OpenOptions::default()
+        };
+        open(&dir, "file", truncate).unwrap();
+        assert_eq!(dir.read_file("file").unwrap(), b"");
+
+        let create_new = OpenOptions {
+            write: true,
+            create_new: true,
+            ..OpenOptions::default()
+        };
+        assert_eq!(open(&dir, "file", create_new).err(), Some(WASI_EEXIST));
+        assert!(open(&dir, "new", create_new).is_ok());
+        assert_eq!(dir.read_file("new").unwrap(), b"");
+        assert_eq!(open(&dir, "missing", read_write()).err(), Some(WASI_ENOENT));
+    }
+
+    #[test]
+    fn read_past_the_end() {
+        let dir = data();
+        let mut file = open(&dir, "file", read_write()).unwrap();
+        assert_eq!(read(&mut file, 2), b"fi");
+        assert_eq!(read(&mut file, 10), b"le");
+        assert_eq!(read(&mut file, 10), b"");
+        file.seek(SeekFrom::Start(1 << 40)).unwrap();
+        assert_eq!(read(&mut file, 10), b"");
+        assert_eq!(file.remaining(), 0);
+    }
+
+    #[test]
+    fn size_limits() {
+        let dir = data().with_limits(Limits {
+            max_file_size: 16,
+            max_size: 24,
+        });
+        let mut file = open(&dir, "file", read_write()).unwrap();
+        file.seek(SeekFrom::Start(1 << 40)).unwrap();
+        assert_eq!(write(&mut file, "x"), Err(WASI_EFBIG));
+        file.seek(SeekFrom::Start(u64::MAX)).unwrap();
+        assert_eq!(write(&mut file, "x"), Err(WASI_EFBIG));
+        file.seek(SeekFrom::Start(15)).unwrap();
+        assert_eq!(write(&mut file, "xx"), Err(WASI_EFBIG));
+        assert_eq!(write(&mut file, "x"), Ok(1));
+        assert_eq!(dir.read_file("file").unwrap().len(), 16);
+
+        // `file` has 16 bytes and `dir/nested` 6, which leaves 2 bytes.
+        let mut nested = open(&dir, "dir/nested", read_write()).unwrap();
+        nested.seek(SeekFrom::Start(8)).unwrap();
+        assert_eq!(write(&mut nested, "x"), Err(WASI_ENOSPC));
+        nested.seek(SeekFrom::Start(7)).unwrap();
+        assert_eq!(write(&mut nested, "x"), Ok(1));
+
+        // Truncating frees up space again.
+        let truncate = OpenOptions {
+            write: true,
+            truncate: true,
+            ..OpenOptions::default()
+        };
+        open(&dir, "file", truncate).unwrap();
+        nested.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(write(&mut nested, &"x".repeat(16)), Ok(16));
+    }
+
+    #[test]
+    fn directory_entry_cookies() {
+        let dir = VirtualDir::new("/data".to_owned())
+            .with_file("a", "")
+            .unwrap()
+            .with_file("b", "")
+            .unwrap()
+            .with_file("c", "")
+            .unwrap();
+        let root = VirtualDirectory::preopened(&dir);
+        let mut buffer = [0; 1024];
+        let used = root.read_entries(&mut buffer, 0).unwrap();
+        let entries = dirents(&buffer[..used]);
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [".", "..", "a", "b", "c"]);
+        // Each entry points at the next one.
+        let cookies: Vec<u64> = entries.iter().map(|(_, cookie)| *cookie).collect();
+        assert_eq!(cookies, [1, 2, 3, 4, 5]);
+
+        // Continuing from the cookie of `a` returns the following entries.
+        let used = root.read_entries(&mut buffer, 3).unwrap();
+        let entries = dirents(&buffer[..used]);
+        assert_eq!(entries[0], ("b".to_owned(), 4));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(root.read_entries(&mut buffer, 5), Ok(0));
+
+        // A buffer that is too small is filled completely.
+        let mut small = [0; WASI_DIRENT_SIZE + 1];
+        assert_eq!(root.read_entries(&mut small, 0), Ok(small.len()));
+    }
+
+    // Parses `(name, next cookie)` pairs back from `dirent`s.
+    fn dirents(mut buffer: &[u8]) -> Vec<(String, u64)> {
+        let mut entries = Vec::new();
+        while !buffer.is_empty() {
+            let cookie = u64::from_le_bytes(buffer[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(buffer[16..20].try_into().unwrap()) as usize;
+            let name = &buffer[WASI_DIRENT_SIZE..WASI_DIRENT_SIZE + len];
+            entries.push((String::from_utf8(name.to_vec()).unwrap(), cookie));
+            buffer = &buffer[WASI_DIRENT_SIZE + len..];
+        }
+        entries
+    }
+
+    #[test]
+    fn mappings_with_colons() {
+        let dir: VirtualDir = "::C:\\data".parse().unwrap();
+        assert_eq!(dir.guest_path(), "C:\\data");
+    }
+}
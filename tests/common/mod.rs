@@ -0,0 +1,26 @@
+//! Helpers shared by the tests that run guest code as lunatic processes.
+
+use anyhow::Result;
+use smol::future;
+
+use lunatic_vm::module::LunaticModule;
+use lunatic_vm::process::{
+    FunctionLookup, MemoryChoice, Process, ProcessConfig, ProcessOutcome, EXECUTOR,
+};
+
+/// Compiles the module in text format with the default normalisation pipeline.
+pub fn module(wat: &str) -> LunaticModule {
+    let wasm = wat::parse_str(wat).unwrap();
+    LunaticModule::new(wasm).unwrap()
+}
+
+/// Spawns a process running `_start` and drives the executor until it finishes.
+pub fn run(wat: &str, config: ProcessConfig) -> Result<ProcessOutcome> {
+    let process = Process::spawn(
+        module(wat),
+        FunctionLookup::Name("_start"),
+        MemoryChoice::New,
+        config,
+    );
+    future::block_on(EXECUTOR.run(process.join()))
+}
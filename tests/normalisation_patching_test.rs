@@ -1,13 +1,14 @@
 //! The normalisation phase does many code transformations.
 //! This test checks if all code transformations applied during the normalisation phase are correct.
 //! All WASM files in the ./normalisation_patching_test folder are patched and compared to the expected output.
+//! Files in the ./normalisation_patching_test/weighted folder are patched with the weighted cost model.
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 
-use lunatic_vm::normalisation::patch;
+use lunatic_vm::normalisation::{CostModel, Pipeline};
 
 fn main() {
     let mut tests = Vec::new();
@@ -39,7 +40,12 @@ fn run_tests(tests: Vec<PathBuf>) {
         let expected_output = input_expected_output.last().unwrap();
 
         // Run test on one file
-        let output_wasm = run_test(input);
+        let cost_model = if test.parent().unwrap().ends_with("weighted") {
+            CostModel::Weighted
+        } else {
+            CostModel::Uniform
+        };
+        let output_wasm = run_test(input, cost_model);
         let output_wat = wasmprinter::print_bytes(&output_wasm).unwrap();
 
         // Normalize expected_output
@@ -55,7 +61,10 @@ fn run_tests(tests: Vec<PathBuf>) {
     }
 }
 
-fn run_test(input: &str) -> Vec<u8> {
+fn run_test(input: &str, cost_model: CostModel) -> Vec<u8> {
     let wasm = wat::parse_str(input).unwrap();
-    patch(&wasm).unwrap().1
+    Pipeline::with_cost_model(cost_model)
+        .apply(&wasm)
+        .unwrap()
+        .1
 }
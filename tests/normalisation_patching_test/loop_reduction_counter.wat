@@ -0,0 +1,63 @@
+;; Input
+(module
+    (func (export "spin") (param i32)
+        loop
+            local.get 0
+            i32.const 1
+            i32.sub
+            local.tee 0
+            br_if 0
+        end
+    )
+)
+
+;; EXPECTED-RESULT:
+(module
+    (import "lunatic" "reduction_limit" (global (;0 reduction limit ;) i32))
+    (global (;1 reduction counter ;) (mut i32) (i32.const 0))
+    (type (;0 yield type ;) (func))
+    (type (;1;) (func (param i32)))
+    (import "lunatic" "yield_" (func (;0;) (type 0)))
+
+    (func (;1;) (type 1) (param i32)
+        block  ;; Reduction counter logic
+            global.get 1
+            i32.const 1
+            i32.add
+            global.set 1
+            global.get 1
+            global.get 0
+            i32.gt_u
+            if
+                call 0
+                i32.const 0
+                global.set 1
+            else
+            end
+        end
+        loop
+            block  ;; Reduction counter logic, runs on every iteration
+                global.get 1
+                i32.const 1
+                i32.add
+                global.set 1
+                global.get 1
+                global.get 0
+                i32.gt_u
+                if
+                    call 0
+                    i32.const 0
+                    global.set 1
+                else
+                end
+            end
+            local.get 0
+            i32.const 1
+            i32.sub
+            local.tee 0
+            br_if 0
+        end
+    )
+
+    (export "spin" (func 1))
+)
@@ -6,7 +6,8 @@
 ;; EXPECTED-RESULT:
 (module
     (type (;0;) (func))
+    (import "lunatic" "reduction_limit" (global (;0;) i32))
     (import "lunatic" "yield_" (func (;0;) (type 0)))
     (import "lunatic" "memory" (memory (;0;) 17))
-    (global (;0;) (mut i32) (i32.const 0))
+    (global (;1;) (mut i32) (i32.const 0))
 )
\ No newline at end of file
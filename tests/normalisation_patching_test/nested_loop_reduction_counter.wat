@@ -0,0 +1,87 @@
+;; Input
+(module
+    (func (export "nested") (param i32)
+        block
+            loop
+                local.get 0
+                if
+                    loop
+                        br 0
+                    end
+                end
+                br 0
+            end
+        end
+    )
+)
+
+;; EXPECTED-RESULT:
+(module
+    (import "lunatic" "reduction_limit" (global (;0 reduction limit ;) i32))
+    (global (;1 reduction counter ;) (mut i32) (i32.const 0))
+    (type (;0 yield type ;) (func))
+    (type (;1;) (func (param i32)))
+    (import "lunatic" "yield_" (func (;0;) (type 0)))
+
+    (func (;1;) (type 1) (param i32)
+        block  ;; Reduction counter logic
+            global.get 1
+            i32.const 1
+            i32.add
+            global.set 1
+            global.get 1
+            global.get 0
+            i32.gt_u
+            if
+                call 0
+                i32.const 0
+                global.set 1
+            else
+            end
+        end
+        block
+            loop  ;; Loops nested in blocks are patched
+                block
+                    global.get 1
+                    i32.const 1
+                    i32.add
+                    global.set 1
+                    global.get 1
+                    global.get 0
+                    i32.gt_u
+                    if
+                        call 0
+                        i32.const 0
+                        global.set 1
+                    else
+                    end
+                end
+                local.get 0
+                if
+                    loop  ;; Loops nested in ifs and other loops are patched
+                        block
+                            global.get 1
+                            i32.const 1
+                            i32.add
+                            global.set 1
+                            global.get 1
+                            global.get 0
+                            i32.gt_u
+                            if
+                                call 0
+                                i32.const 0
+                                global.set 1
+                            else
+                            end
+                        end
+                        br 0
+                    end
+                else
+                end
+                br 0
+            end
+        end
+    )
+
+    (export "nested" (func 1))
+)
@@ -7,24 +7,25 @@
 
 ;; EXPECTED-RESULT:
 (module
-    (global (;0 reduction counter ;) (mut i32) (i32.const 0))
+    (import "lunatic" "reduction_limit" (global (;0 reduction limit ;) i32))
+    (global (;1 reduction counter ;) (mut i32) (i32.const 0))
     (type (;0 yield type ;) (func))
     (type (;1;) (func (result i32)))
     (import "lunatic" "yield_" (func (;0;) (type 0)))
 
     (func (;1;) (type 1) (result i32)
         block  ;; Reduction counter logic
-            global.get 0
+            global.get 1
             i32.const 1
             i32.add
-            global.set 0
+            global.set 1
+            global.get 1
             global.get 0
-            i32.const 10000
-            i32.gt_s
+            i32.gt_u
             if
                 call 0
                 i32.const 0
-                global.set 0
+                global.set 1
             else
             end
         end
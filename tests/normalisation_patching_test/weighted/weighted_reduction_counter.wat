@@ -0,0 +1,97 @@
+;; Input
+(module
+    (func (export "sum") (param i32) (result i32)
+        (local i32)
+        loop
+            local.get 0
+            if
+                local.get 1
+                local.get 0
+                i32.add
+                local.set 1
+            end
+            local.get 0
+            i32.const 1
+            i32.sub
+            local.tee 0
+            br_if 0
+        end
+        local.get 1
+    )
+)
+
+;; EXPECTED-RESULT:
+(module
+    (import "lunatic" "reduction_limit" (global (;0 reduction limit ;) i32))
+    (global (;1 reduction counter ;) (mut i32) (i32.const 0))
+    (type (;0 yield type ;) (func))
+    (type (;1;) (func (param i32) (result i32)))
+    (import "lunatic" "yield_" (func (;0;) (type 0)))
+
+    (func (;1;) (type 1) (param i32) (result i32)
+        (local i32)
+        block  ;; The function body has 2 instructions, the loop is charged on each iteration
+            global.get 1
+            i32.const 2
+            i32.add
+            global.set 1
+            global.get 1
+            global.get 0
+            i32.gt_u
+            if
+                call 0
+                i32.const 0
+                global.set 1
+            else
+            end
+        end
+        loop
+            block  ;; The loop body has 7 instructions, the `if` branch is charged when taken
+                global.get 1
+                i32.const 7
+                i32.add
+                global.set 1
+                global.get 1
+                global.get 0
+                i32.gt_u
+                if
+                    call 0
+                    i32.const 0
+                    global.set 1
+                else
+                end
+            end
+            local.get 0
+            if
+                block  ;; The `if` branch has 4 instructions, the empty `else` branch is free
+                    global.get 1
+                    i32.const 4
+                    i32.add
+                    global.set 1
+                    global.get 1
+                    global.get 0
+                    i32.gt_u
+                    if
+                        call 0
+                        i32.const 0
+                        global.set 1
+                    else
+                    end
+                end
+                local.get 1
+                local.get 0
+                i32.add
+                local.set 1
+            else
+            end
+            local.get 0
+            i32.const 1
+            i32.sub
+            local.tee 0
+            br_if 0
+        end
+        local.get 1
+    )
+
+    (export "sum" (func 1))
+)
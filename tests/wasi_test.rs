@@ -0,0 +1,177 @@
+//! Runs small guests against the WASI implementation.
+
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use smol::{future, Timer};
+
+use lunatic_vm::process::{
+    FunctionLookup, MemoryChoice, Process, ProcessConfig, ProcessOutcome, EXECUTOR,
+};
+use lunatic_vm::wasi::clock::VirtualClock;
+use lunatic_vm::wasi::vfs::VirtualDir;
+
+// Polls a relative monotonic clock subscription of one hour and exits with the returned errno.
+const POLL_ONE_HOUR: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "poll_oneoff" (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+  (memory (export "memory") 1)
+  ;; userdata, clock event, monotonic clock, 3600s timeout, no precision, relative
+  (data (i32.const 0)
+    "\2a\00\00\00\00\00\00\00" "\00\00\00\00\00\00\00\00"
+    "\01\00\00\00\00\00\00\00" "\00\a0\b8\30\46\03\00\00"
+    "\00\00\00\00\00\00\00\00" "\00\00\00\00\00\00\00\00")
+  (func (export "_start")
+    (call $proc_exit (call $poll_oneoff (i32.const 0) (i32.const 64) (i32.const 1) (i32.const 128)))))
+"#;
+
+#[test]
+fn poll_waits_on_virtual_clock() {
+    let clock = Arc::new(VirtualClock::new(Duration::from_secs(0)));
+    let config = ProcessConfig {
+        clock: clock.clone(),
+        ..ProcessConfig::default()
+    };
+
+    let started = Instant::now();
+    let advance = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        clock.advance(Duration::from_secs(3600));
+    });
+    let outcome = common::run(POLL_ONE_HOUR, config).unwrap();
+    advance.join().unwrap();
+    assert_eq!(outcome, ProcessOutcome::Exited(0));
+    assert!(started.elapsed() < Duration::from_secs(60));
+}
+
+#[test]
+fn poll_events_out_of_bounds() {
+    // The events buffer starts right before the end of the memory.
+    let wat = POLL_ONE_HOUR.replace("(i32.const 64)", "(i32.const 65530)");
+    let outcome = common::run(&wat, ProcessConfig::default()).unwrap();
+    assert_eq!(outcome, ProcessOutcome::Exited(21)); // WASI_EFAULT
+}
+
+#[test]
+fn drop_process_suspended_in_host_call() {
+    let clock = Arc::new(VirtualClock::new(Duration::from_secs(0)));
+    let config = ProcessConfig {
+        clock: clock.clone(),
+        ..ProcessConfig::default()
+    };
+    let process = Process::spawn(
+        common::module(POLL_ONE_HOUR),
+        FunctionLookup::Name("_start"),
+        MemoryChoice::New,
+        config,
+    );
+    // Run until the process is suspended in `poll_oneoff`, the clock is never advanced.
+    future::block_on(EXECUTOR.run(Timer::after(Duration::from_millis(100))));
+
+    // Dropping the suspended stack must neither be reported as a trap nor unwind into the
+    // executor.
+    let cancelled = future::block_on(EXECUTOR.run(process.join().cancel()));
+    assert!(cancelled.is_none());
+    clock.advance(Duration::from_secs(3600));
+
+    // Processes spawned afterwards still run.
+    let wat = POLL_ONE_HOUR.replace("(i32.const 64)", "(i32.const 65530)");
+    let outcome = common::run(&wat, ProcessConfig::default()).unwrap();
+    assert_eq!(outcome, ProcessOutcome::Exited(21)); // WASI_EFAULT
+}
+
+// Exits with the errno of `args_get(0, $buf)`.
+const ARGS_GET: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+  (memory (export "memory") 1)
+  (func (export "_start")
+    (call $proc_exit (call $args_get (i32.const 0) (i32.const $buf)))))
+"#;
+
+fn args_get(args: &[&str], buffer: u32) -> ProcessOutcome {
+    let wat = ARGS_GET.replace("$buf", &buffer.to_string());
+    let config = ProcessConfig {
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        ..ProcessConfig::default()
+    };
+    common::run(&wat, config).unwrap()
+}
+
+#[test]
+fn args_get_out_of_bounds() {
+    // "ab\0" ends right at the end of the memory.
+    assert_eq!(args_get(&["ab"], 65533), ProcessOutcome::Exited(0));
+    assert_eq!(args_get(&["ab"], 65534), ProcessOutcome::Exited(21)); // WASI_EFAULT
+    assert_eq!(args_get(&["a", "b"], 65532), ProcessOutcome::Exited(0));
+    assert_eq!(args_get(&["a", "b"], 65533), ProcessOutcome::Exited(21));
+}
+
+#[test]
+fn namespaces_share_file_descriptors() {
+    // Stdout closed through preview1 is gone in preview0 too, the exit code is `WASI_EBADF`.
+    let wat = r#"
+    (module
+      (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+      (import "wasi_unstable" "fd_fdstat_get" (func $fd_fdstat_get (param i32 i32) (result i32)))
+      (import "wasi_unstable" "proc_exit" (func $proc_exit (param i32)))
+      (memory (export "memory") 1)
+      (func (export "_start")
+        (drop (call $fd_close (i32.const 1)))
+        (call $proc_exit (call $fd_fdstat_get (i32.const 1) (i32.const 0)))))
+    "#;
+    let outcome = common::run(wat, ProcessConfig::default()).unwrap();
+    assert_eq!(outcome, ProcessOutcome::Exited(8));
+}
+
+// Opens `a.txt` in the first preopened directory with the rights `$rights`, then exits with the
+// errno of writing to it.
+const WRITE_OPENED_FILE: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+  (memory (export "memory") 1)
+  ;; path, iovec pointing at the path
+  (data (i32.const 0) "a.txt")
+  (data (i32.const 16) "\00\00\00\00\05\00\00\00")
+  (func (export "_start")
+    (local $errno i32)
+    (local.set $errno
+      (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 5)
+        (i32.const 0) (i64.const $rights) (i64.const 0) (i32.const 0) (i32.const 32)))
+    (if (local.get $errno) (then (call $proc_exit (local.get $errno))))
+    (call $proc_exit
+      (call $fd_write (i32.load (i32.const 32)) (i32.const 16) (i32.const 1) (i32.const 40)))))
+"#;
+
+fn write_opened_file(rights: u64) -> (ProcessOutcome, Vec<u8>) {
+    let dir = VirtualDir::new("/data".to_string())
+        .with_file("a.txt", "hello")
+        .unwrap();
+    let config = ProcessConfig {
+        virtual_dirs: vec![dir.clone()],
+        ..ProcessConfig::default()
+    };
+    let wat = WRITE_OPENED_FILE.replace("$rights", &rights.to_string());
+    let outcome = common::run(&wat, config).unwrap();
+    (outcome, dir.read_file("a.txt").unwrap())
+}
+
+#[test]
+fn rights_are_checked_on_virtual_files() {
+    // Only `WASI_RIGHT_FD_READ`
+    let (outcome, contents) = write_opened_file(1 << 1);
+    assert_eq!(outcome, ProcessOutcome::Exited(76)); // WASI_ENOTCAPABLE
+    assert_eq!(contents, b"hello");
+
+    // `WASI_RIGHT_FD_READ` and `WASI_RIGHT_FD_WRITE`
+    let (outcome, contents) = write_opened_file(1 << 1 | 1 << 6);
+    assert_eq!(outcome, ProcessOutcome::Exited(0));
+    assert_eq!(contents, b"a.txt");
+}
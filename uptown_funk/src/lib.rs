@@ -2,6 +2,7 @@ pub mod state;
 pub mod types;
 pub mod wasmer;
 
+use std::any::Any;
 use std::cell::{Ref, RefCell, RefMut};
 use std::convert::Into;
 use std::fmt::Debug;
@@ -87,18 +88,74 @@ impl<S, E: InstanceEnvironment> StateWrapper<S, E> {
     }
 }
 
+/// Polls the future of an async host function, catching panics raised while polling it.
+///
+/// Host functions wrap only the polling in `catch_unwind`, never the suspension of the instance
+/// that awaits this future. Unwinding that starts at the suspension point belongs to the stack
+/// switching runtime and must not be turned into a trap.
+pub struct CatchUnwind<F> {
+    future: F,
+}
+
+impl<F: std::future::Future> CatchUnwind<F> {
+    pub fn new(future: F) -> Self {
+        Self { future }
+    }
+}
+
+impl<F: std::future::Future> std::future::Future for CatchUnwind<F> {
+    type Output = std::thread::Result<F::Output>;
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        // The future is never moved out of the pinned wrapper.
+        let future = unsafe { self.map_unchecked_mut(|this| &mut this.future) };
+        let poll = std::panic::AssertUnwindSafe(|| future.poll(cx));
+        match std::panic::catch_unwind(poll) {
+            Ok(poll) => poll.map(Ok),
+            Err(panic) => std::task::Poll::Ready(Err(panic)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Trap {
     message: String,
+    exit_code: Option<i32>,
 }
 
 impl Trap {
     pub fn new<I: Into<String>>(message: I) -> Self {
         Self {
             message: message.into(),
+            exit_code: None,
+        }
+    }
+
+    /// A trap that stops the instance because it requested to exit (e.g. WASI's `proc_exit`).
+    /// The runtime can tell it apart from other traps and retrieve the exit code.
+    pub fn exit(exit_code: i32) -> Self {
+        Self {
+            message: format!("Exited with code {}", exit_code),
+            exit_code: Some(exit_code),
         }
     }
 
+    /// Turns the payload of a panic inside of a host function into a trap, so that only the
+    /// calling instance is stopped.
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.as_str()
+        } else {
+            "Box<Any>"
+        };
+        Trap::new(format!("Host function panicked: {}", message))
+    }
+
     pub fn try_option<R: Debug>(result: Option<R>) -> Result<R, Trap> {
         match result {
             Some(r) => Ok(r),
@@ -121,7 +178,10 @@ impl Trap {
 
 impl From<Trap> for wasmtime::Trap {
     fn from(trap: Trap) -> Self {
-        wasmtime::Trap::new(trap.message)
+        match trap.exit_code {
+            Some(exit_code) => wasmtime::Trap::i32_exit(exit_code),
+            None => wasmtime::Trap::new(trap.message),
+        }
     }
 }
 
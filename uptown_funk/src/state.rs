@@ -1,5 +1,9 @@
 use std::collections::HashMap;
 
+/// Maps resources to ids that can be handed out to guests.
+///
+/// Ids start at 1, so 0 can be used by host functions to signal the absence of a resource
+/// without ever aliasing a valid id.
 pub struct HashMapStore<T> {
     id_seed: u32,
     store: HashMap<u32, T>,
@@ -8,7 +12,7 @@ pub struct HashMapStore<T> {
 impl<T> HashMapStore<T> {
     pub fn new() -> Self {
         Self {
-            id_seed: 0,
+            id_seed: 1,
             store: HashMap::new(),
         }
     }
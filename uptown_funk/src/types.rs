@@ -26,6 +26,23 @@ impl WasmType for u8 {
     }
 }
 
+impl WasmType for u16 {
+    type Value = u16;
+
+    fn copy_to(&self, mem: &mut [u8]) {
+        mem[..2].copy_from_slice(&self.to_le_bytes());
+    }
+
+    #[inline]
+    fn len() -> usize {
+        2
+    }
+
+    fn value_from_memory(mem: &[u8]) -> Self::Value {
+        u16::from_le_bytes([mem[0], mem[1]])
+    }
+}
+
 impl WasmType for u32 {
     type Value = u32;
 
@@ -76,6 +93,14 @@ impl<'a, S, T: WasmType> Pointer<'a, S, T> {
         T::value_from_memory(&self.mem[self.loc..])
     }
 
+    /// Returns `true` if `count` values starting at the pointer fit into the memory.
+    pub fn fits(&self, count: usize) -> bool {
+        count
+            .checked_mul(T::len())
+            .and_then(|len| self.loc.checked_add(len))
+            .map_or(false, |end| end <= self.mem.len())
+    }
+
     pub fn next(self) -> Option<Self> {
         let loc = self.loc + T::len();
         if loc >= self.mem.len() {
@@ -87,6 +112,11 @@ impl<'a, S, T: WasmType> Pointer<'a, S, T> {
 }
 
 impl<'a, S> Pointer<'a, S, u8> {
+    /// Returns `len` bytes starting at the pointer or `None` if they don't fit into the memory.
+    pub fn slice(&self, len: usize) -> Option<&[u8]> {
+        self.mem.get(self.loc..self.loc.checked_add(len)?)
+    }
+
     pub fn copy_slice(self, slice: &[u8]) -> Option<Self> {
         let loc = self.loc + slice.len();
         if loc > self.mem.len() {
@@ -124,4 +154,4 @@ impl<'a, S, T: WasmType> FromWasmU32<'a> for Pointer<'a, S, T> {
             _type: PhantomData::default(),
         })
     }
-}
\ No newline at end of file
+}
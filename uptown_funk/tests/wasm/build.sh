@@ -5,4 +5,5 @@ rustc ref_str.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --
 rustc ioslices.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --crate-type=cdylib -C opt-level=3
 rustc custom_types.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --crate-type=cdylib -C opt-level=3
 rustc custom_types_return.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --crate-type=cdylib -C opt-level=3
-rustc mutable_state.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --crate-type=cdylib -C opt-level=3
\ No newline at end of file
+rustc mutable_state.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --crate-type=cdylib -C opt-level=3
+rustc panic.rs -C link-args=--import-memory --target=wasm32-unknown-unknown --crate-type=cdylib -C opt-level=3
\ No newline at end of file
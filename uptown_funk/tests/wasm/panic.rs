@@ -0,0 +1,9 @@
+#[link(wasm_import_module = "env")]
+extern "C" {
+    fn fail();
+}
+
+#[export_name = "test"]
+pub extern "C" fn test() {
+    unsafe { fail() };
+}
@@ -10,10 +10,25 @@ pub fn wrap(namespace: &LitStr, method: &ImplItemMethod) -> Result<TokenStream2,
     let method_name = &signature.ident;
     let method_name_as_str = LitStr::new(&method_name.to_string(), method_name.span());
 
-    // If it's an async function wrap it in an async block.
-    let maybe_async = match signature.asyncness {
-        Some(_) => quote! { state_wrapper.instance_environment().async_ },
-        None => quote! { std::convert::identity },
+    // If it's an async function suspend the instance until the returned future is ready. Only
+    // polling the future is guarded against panics, if the suspension itself unwinds (e.g. the
+    // stack is torn down while the instance is suspended) the unwinding is resumed.
+    let (suspended, maybe_async) = match signature.asyncness {
+        Some(_) => (
+            quote! { let mut suspended = false; },
+            quote! {
+                suspended = true;
+                let result = state_wrapper
+                    .instance_environment()
+                    .async_(uptown_funk::CatchUnwind::new(result));
+                suspended = false;
+                let result = match result {
+                    Ok(result) => result,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                };
+            },
+        ),
+        None => (quote! { let suspended = false; }, quote! {}),
     };
 
     let (
@@ -31,13 +46,23 @@ pub fn wrap(namespace: &LitStr, method: &ImplItemMethod) -> Result<TokenStream2,
         let closure = move |state: &uptown_funk::wasmer::WasmerStateWrapper<Self, E>, #guest_signature_input|
          -> Result<#guest_signature_return, wasmtime::Trap> {
             let state_wrapper = state.state_wrapper();
-            #from_guest_input_transformations
-            let result = {
-                let mut borrow = state_wrapper.borrow_state_mut();
-                let result = Self::#method_name(&mut borrow, #host_call_signature);
-                #maybe_async(result)
-            };
-            Ok(#from_host_return_transformations(result)?)
+            // Panics must not unwind through guest frames.
+            #suspended
+            let call = std::panic::AssertUnwindSafe(|| -> Result<#guest_signature_return, wasmtime::Trap> {
+                #from_guest_input_transformations
+                let result = {
+                    let mut borrow = state_wrapper.borrow_state_mut();
+                    let result = Self::#method_name(&mut borrow, #host_call_signature);
+                    #maybe_async
+                    result
+                };
+                Ok(#from_host_return_transformations(result)?)
+            });
+            match std::panic::catch_unwind(call) {
+                Ok(result) => result,
+                Err(panic) if suspended => std::panic::resume_unwind(panic),
+                Err(panic) => Err(uptown_funk::Trap::from_panic(panic).into()),
+            }
         };
 
         let func = wasmer::Function::new_native_with_env(store, state.clone(), closure);